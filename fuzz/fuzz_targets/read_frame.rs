@@ -0,0 +1,9 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(frame) = shvrpc::streamrw::read_frame(data) {
+        let _ = frame.to_rpcmesage();
+    }
+});
@@ -0,0 +1,13 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use shvproto::{MetaMap, RpcValue};
+use shvrpc::rpcframe::{Protocol, RpcFrame};
+
+fuzz_target!(|data: &[u8]| {
+    let mut meta = MetaMap::new();
+    meta.insert(1, RpcValue::from(1));
+    let frame = RpcFrame::new(Protocol::ChainPack, meta, data.to_vec());
+    let _ = frame.to_rpcmesage();
+    let _ = frame.to_string();
+});
@@ -0,0 +1,21 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use shvrpc::framerw::FrameDecoder;
+use shvrpc::serialrw::SerialFrameDecoder;
+
+fuzz_target!(|data: &[u8]| {
+    // The first byte selects the chunk size the data is pushed to the decoder with and CRC check
+    let Some((flags, data)) = data.split_first() else {
+        return;
+    };
+    let mut decoder = SerialFrameDecoder::new()
+        .with_crc_check(flags & 0x80 != 0)
+        .with_max_frame_size(1024 * 1024);
+    for chunk in data.chunks((flags & 0x7F) as usize + 1) {
+        decoder.push(chunk);
+        while let Ok(Some(frame)) = decoder.next_frame() {
+            let _ = frame.to_rpcmesage();
+        }
+    }
+});
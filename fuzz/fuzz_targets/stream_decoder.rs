@@ -0,0 +1,23 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use shvrpc::framerw::FrameDecoder;
+use shvrpc::streamrw::StreamFrameDecoder;
+
+fuzz_target!(|data: &[u8]| {
+    // The first byte selects the chunk size the data is pushed to the decoder with
+    let Some((chunk_size, data)) = data.split_first() else {
+        return;
+    };
+    let mut decoder = StreamFrameDecoder::new().with_max_frame_size(1024 * 1024);
+    for chunk in data.chunks(*chunk_size as usize + 1) {
+        decoder.push(chunk);
+        loop {
+            match decoder.next_frame() {
+                Ok(Some(frame)) => { let _ = frame.to_rpcmesage(); }
+                Ok(None) => { break }
+                Err(_) => { continue }
+            }
+        }
+    }
+});
@@ -0,0 +1,267 @@
+//! Synchronous counterparts of the async frame readers and writers.
+//!
+//! They work over any `std::io::Read`/`Write` and share the framing code with the async
+//! versions through [`StreamFrameDecoder`], [`SerialFrameDecoder`] and the `write_frame` functions.
+use std::io::{Read, Write};
+use log::*;
+use shvproto::{MetaMap, RpcValue};
+use crate::client::{LoginParams, LoginResult};
+use crate::framerw::FrameDecoder;
+use crate::rpcframe::{Protocol, RpcFrame};
+use crate::rpcmessage::RqId;
+use crate::serialrw::SerialFrameDecoder;
+use crate::streamrw::StreamFrameDecoder;
+use crate::{client, serialrw, streamrw, Error, RpcMessage, RpcMessageMetaTags};
+
+const READ_BUFFER_SIZE: usize = 8 * 1024;
+
+pub trait FrameReader {
+    fn receive_frame(&mut self) -> crate::Result<RpcFrame>;
+
+    /// Returns [`Error::ResetSession`] when the peer requests a session reset.
+    fn receive_message(&mut self) -> crate::Result<RpcMessage> {
+        let frame = self.receive_frame()?;
+        let msg = frame.to_rpcmesage()?;
+        Ok(msg)
+    }
+}
+
+pub trait FrameWriter {
+    fn send_reset_session(&mut self) -> crate::Result<()> {
+        self.send_frame(RpcFrame {
+            protocol: Protocol::ResetSession,
+            meta: MetaMap::new(),
+            data: vec![],
+        })
+    }
+    fn send_frame(&mut self, frame: RpcFrame) -> crate::Result<()>;
+    fn send_message(&mut self, msg: RpcMessage) -> crate::Result<()> {
+        self.send_frame(msg.to_frame()?)
+    }
+    fn send_request(&mut self, shv_path: &str, method: &str, param: Option<RpcValue>) -> crate::Result<RqId> {
+        let rpcmsg = RpcMessage::new_request(shv_path, method, param);
+        let rqid = rpcmsg.request_id().expect("Request ID should exist here.");
+        self.send_message(rpcmsg)?;
+        Ok(rqid)
+    }
+}
+
+fn receive_frame(reader: &mut impl Read, decoder: &mut impl FrameDecoder, buffer: &mut [u8]) -> crate::Result<RpcFrame> {
+    loop {
+        if let Some(frame) = decoder.next_frame()? {
+            return Ok(frame)
+        }
+        let n = reader.read(buffer)?;
+        if n == 0 {
+            return Err(Error::UnexpectedEof)
+        }
+        decoder.push(&buffer[.. n]);
+    }
+}
+
+pub struct StreamFrameReader<R: Read> {
+    reader: R,
+    decoder: StreamFrameDecoder,
+    buffer: Box<[u8]>,
+}
+impl<R: Read> StreamFrameReader<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader,
+            decoder: StreamFrameDecoder::new(),
+            buffer: vec![0; READ_BUFFER_SIZE].into_boxed_slice(),
+        }
+    }
+    pub fn with_max_frame_size(mut self, max_frame_size: usize) -> Self {
+        self.decoder = self.decoder.with_max_frame_size(max_frame_size);
+        self
+    }
+}
+impl<R: Read> FrameReader for StreamFrameReader<R> {
+    fn receive_frame(&mut self) -> crate::Result<RpcFrame> {
+        receive_frame(&mut self.reader, &mut self.decoder, &mut self.buffer)
+    }
+}
+
+pub struct StreamFrameWriter<W: Write> {
+    writer: W,
+}
+impl<W: Write> StreamFrameWriter<W> {
+    pub fn new(writer: W) -> Self {
+        Self { writer }
+    }
+}
+impl<W: Write> FrameWriter for StreamFrameWriter<W> {
+    fn send_frame(&mut self, frame: RpcFrame) -> crate::Result<()> {
+        log!(target: "RpcMsg", Level::Debug, "S<== {}", &frame);
+        let mut buff = Vec::new();
+        streamrw::write_frame(&mut buff, frame)?;
+        self.writer.write_all(&buff)?;
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+pub struct SerialFrameReader<R: Read> {
+    reader: R,
+    decoder: SerialFrameDecoder,
+    buffer: Box<[u8]>,
+}
+impl<R: Read> SerialFrameReader<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader,
+            decoder: SerialFrameDecoder::new(),
+            buffer: vec![0; READ_BUFFER_SIZE].into_boxed_slice(),
+        }
+    }
+    pub fn with_crc_check(mut self, on: bool) -> Self {
+        self.decoder = self.decoder.with_crc_check(on);
+        self
+    }
+    pub fn with_max_frame_size(mut self, max_frame_size: usize) -> Self {
+        self.decoder = self.decoder.with_max_frame_size(max_frame_size);
+        self
+    }
+}
+impl<R: Read> FrameReader for SerialFrameReader<R> {
+    fn receive_frame(&mut self) -> crate::Result<RpcFrame> {
+        receive_frame(&mut self.reader, &mut self.decoder, &mut self.buffer)
+    }
+}
+
+pub struct SerialFrameWriter<W: Write> {
+    writer: W,
+    with_crc: bool,
+}
+impl<W: Write> SerialFrameWriter<W> {
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer,
+            with_crc: false,
+        }
+    }
+    pub fn with_crc_check(mut self, on: bool) -> Self {
+        self.with_crc = on;
+        self
+    }
+}
+impl<W: Write> FrameWriter for SerialFrameWriter<W> {
+    fn send_frame(&mut self, frame: RpcFrame) -> crate::Result<()> {
+        log!(target: "RpcMsg", Level::Debug, "S<== {}", &frame);
+        let mut buff = Vec::new();
+        serialrw::write_frame(&mut buff, frame, self.with_crc)?;
+        self.writer.write_all(&buff)?;
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Blocking version of [`client::login`].
+pub fn login(frame_reader: &mut dyn FrameReader, frame_writer: &mut dyn FrameWriter, login_params: &LoginParams) -> crate::Result<LoginResult> {
+    if login_params.reset_session {
+        frame_writer.send_reset_session()?;
+    }
+    frame_writer.send_message(RpcMessage::new_request("", "hello", None))?;
+    let resp = frame_reader.receive_message()?;
+    frame_writer.send_message(client::login_request(login_params, &resp)?)?;
+    let resp = frame_reader.receive_message()?;
+    client::login_result(&resp)
+}
+
+/// Sends a request and waits for its response.
+///
+/// Signals and other messages received in the meantime are dropped.
+pub fn call(frame_reader: &mut dyn FrameReader, frame_writer: &mut dyn FrameWriter, shv_path: &str, method: &str, param: Option<RpcValue>) -> crate::Result<RpcValue> {
+    let rqid = frame_writer.send_request(shv_path, method, param)?;
+    loop {
+        let frame = frame_reader.receive_frame()?;
+        if frame.is_reset_session() {
+            continue
+        }
+        let msg = frame.to_rpcmesage()?;
+        if msg.is_response() && msg.request_id() == Some(rqid) {
+            return Ok(msg.result()?.clone())
+        }
+        debug!("Message dropped while waiting for response to request {rqid}: {msg}");
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::io::{BufReader, BufWriter};
+    use std::net::{TcpListener, TcpStream};
+    use crate::client::Password;
+    use crate::rpcmessage::{RpcError, RpcErrorCode};
+    use crate::serialrw::{ATX, STX};
+    use super::*;
+
+    #[test]
+    fn test_serial_round_trip() {
+        let frames: Vec<_> = [
+            RpcMessage::new_request("foo/bar", "baz", Some("hello".into())),
+            RpcMessage::new_request("test", "ping", Some(RpcValue::from(vec![STX, ATX]))),
+        ].iter().map(|msg| msg.to_frame().unwrap()).collect();
+        for with_crc in [false, true] {
+            let mut buff = vec![];
+            let mut wr = SerialFrameWriter::new(&mut buff).with_crc_check(with_crc);
+            for frame in &frames {
+                wr.send_frame(frame.clone()).unwrap();
+            }
+            let mut rd = SerialFrameReader::new(&*buff).with_crc_check(with_crc);
+            for frame in &frames {
+                assert_eq!(&rd.receive_frame().unwrap(), frame);
+            }
+            assert!(rd.receive_frame().unwrap_err().is_eof());
+        }
+    }
+
+    #[test]
+    fn test_login_and_call() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = StreamFrameReader::new(BufReader::new(stream.try_clone().unwrap()));
+            let mut writer = StreamFrameWriter::new(BufWriter::new(stream));
+            let users = HashMap::from([("test".to_string(), Password::from("secret"))]);
+            // The async server side of the login is exercised elsewhere, answer the handshake by hand
+            let hello = reader.receive_message().unwrap();
+            let mut resp = hello.prepare_response().unwrap();
+            let mut result = shvproto::Map::new();
+            result.insert("nonce".into(), RpcValue::from("1234"));
+            resp.set_result(RpcValue::from(result));
+            writer.send_message(resp).unwrap();
+            let login = reader.receive_message().unwrap();
+            let params = login.param().unwrap().as_map().get("login").unwrap().as_map().clone();
+            let password = params.get("password").unwrap().as_str();
+            assert!(crate::server::check_password(crate::client::LoginType::SHA1, password, "1234", users.get("test").unwrap()));
+            let mut resp = login.prepare_response().unwrap();
+            let mut result = shvproto::Map::new();
+            result.insert("clientId".into(), RpcValue::from(7));
+            resp.set_result(RpcValue::from(result));
+            writer.send_message(resp).unwrap();
+            let rq = reader.receive_message().unwrap();
+            writer.send_message(RpcMessage::new_signal("test", "chng", Some(1.into()))).unwrap();
+            let mut resp = rq.prepare_response().unwrap();
+            resp.set_result("pong");
+            writer.send_message(resp).unwrap();
+            let rq = reader.receive_message().unwrap();
+            let mut resp = rq.prepare_response().unwrap();
+            resp.set_error(RpcError::new(RpcErrorCode::MethodNotFound, "no such method"));
+            writer.send_message(resp).unwrap();
+        });
+        let stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        let mut reader = StreamFrameReader::new(BufReader::new(stream.try_clone().unwrap()));
+        let mut writer = StreamFrameWriter::new(BufWriter::new(stream));
+        let params = LoginParams { user: "test".into(), password: Password::from("secret"), ..Default::default() };
+        let result = login(&mut reader, &mut writer, &params).unwrap();
+        assert_eq!(result.client_id(), Some(7));
+        let result = call(&mut reader, &mut writer, ".app", "ping", None).unwrap();
+        assert_eq!(result.as_str(), "pong");
+        let err = call(&mut reader, &mut writer, ".app", "foo", None).unwrap_err();
+        assert!(matches!(err, Error::Rpc(RpcError { code: RpcErrorCode::MethodNotFound, .. })));
+        server.join().unwrap();
+    }
+}
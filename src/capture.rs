@@ -0,0 +1,316 @@
+//! Frame capture recording and replay.
+//!
+//! [`CaptureFrameReader`] and [`CaptureFrameWriter`] wrap a frame reader or writer and record
+//! every frame passing through to a [`Capture`]. The capture file starts with [`MAGIC`] followed
+//! by the records, each record consists of:
+//!
+//! * timestamp, microseconds since the Unix epoch, `u64` big endian
+//! * direction, `0` received, `1` sent
+//! * data length, `u32` big endian
+//! * frame data as written by [`streamrw::write_frame`]
+//!
+//! [`ReplayFrameReader`] plays a capture back.
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use async_trait::async_trait;
+use futures_timer::Delay;
+use log::*;
+use crate::framerw::{FrameReader, FrameWriter};
+use crate::rpcframe::RpcFrame;
+use crate::streamrw;
+use crate::Error;
+
+pub const MAGIC: &[u8; 8] = b"SHVCAP\x00\x01";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Direction {
+    Received = 0,
+    Sent = 1,
+}
+
+#[derive(Clone, Debug)]
+pub struct CaptureRecord {
+    pub timestamp: SystemTime,
+    pub direction: Direction,
+    pub data: Vec<u8>,
+}
+impl CaptureRecord {
+    pub fn frame(&self) -> crate::Result<RpcFrame> {
+        streamrw::read_frame(&self.data)
+    }
+    pub fn write(&self, writer: &mut impl Write) -> crate::Result<()> {
+        let timestamp = self.timestamp.duration_since(UNIX_EPOCH).unwrap_or_default().as_micros() as u64;
+        let len = u32::try_from(self.data.len()).map_err(|_| Error::FrameTooLarge { size: self.data.len(), max_size: u32::MAX as usize })?;
+        writer.write_all(&timestamp.to_be_bytes())?;
+        writer.write_all(&[self.direction as u8])?;
+        writer.write_all(&len.to_be_bytes())?;
+        writer.write_all(&self.data)?;
+        Ok(())
+    }
+    /// Returns `Ok(None)` at the end of the capture.
+    pub fn read(reader: &mut impl Read) -> crate::Result<Option<Self>> {
+        let mut timestamp = [0u8; 8];
+        match reader.read_exact(&mut timestamp) {
+            Ok(()) => { }
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => { return Ok(None) }
+            Err(err) => { return Err(err.into()) }
+        }
+        let mut direction = [0u8; 1];
+        reader.read_exact(&mut direction)?;
+        let direction = match direction[0] {
+            0 => { Direction::Received }
+            1 => { Direction::Sent }
+            b => { return Err(Error::Framing(format!("Invalid capture record direction: {b}"))) }
+        };
+        let mut len = [0u8; 4];
+        reader.read_exact(&mut len)?;
+        let mut data = vec![0u8; u32::from_be_bytes(len) as usize];
+        reader.read_exact(&mut data)?;
+        Ok(Some(Self {
+            timestamp: UNIX_EPOCH + Duration::from_micros(u64::from_be_bytes(timestamp)),
+            direction,
+            data,
+        }))
+    }
+}
+
+/// Capture file shared by the capturing readers and writers.
+///
+/// The records are written by a thread of the capture, so the file IO does not
+/// block the executor running the capturing readers and writers.
+#[derive(Clone)]
+pub struct Capture {
+    sender: mpsc::Sender<CaptureRecord>,
+    writer_thread: Arc<Mutex<Option<JoinHandle<crate::Result<()>>>>>,
+}
+impl Capture {
+    pub fn create(path: impl AsRef<Path>) -> crate::Result<Self> {
+        Self::new(BufWriter::new(File::create(path)?))
+    }
+    pub fn new<W: Write + Send + 'static>(mut writer: W) -> crate::Result<Self> {
+        writer.write_all(MAGIC)?;
+        writer.flush()?;
+        let (sender, receiver) = mpsc::channel();
+        let writer_thread = thread::spawn(move || {
+            write_records(writer, receiver).inspect_err(|err| warn!("Frame capture stopped: {err}"))
+        });
+        Ok(Self { sender, writer_thread: Arc::new(Mutex::new(Some(writer_thread))) })
+    }
+    pub fn record(&self, direction: Direction, frame: &RpcFrame) -> crate::Result<()> {
+        let mut data = Vec::new();
+        streamrw::write_frame(&mut data, frame.clone())?;
+        let record = CaptureRecord { timestamp: SystemTime::now(), direction, data };
+        // The writer thread is gone only after a write error
+        self.sender.send(record).map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        Ok(())
+    }
+    /// Waits until all the records are written to the capture file.
+    ///
+    /// The capturing readers and writers, and other clones of the capture, must be dropped before.
+    pub fn close(self) -> crate::Result<()> {
+        let Self { sender, writer_thread } = self;
+        drop(sender);
+        let writer_thread = writer_thread.lock().map_err(|_| io::Error::other("Capture writer poisoned"))?.take();
+        match writer_thread {
+            Some(writer_thread) => { writer_thread.join().map_err(|_| io::Error::other("Capture writer panicked"))? }
+            None => { Ok(()) }
+        }
+    }
+    pub fn reader<R: FrameReader + Send>(&self, reader: R) -> CaptureFrameReader<R> {
+        CaptureFrameReader { reader, capture: self.clone() }
+    }
+    pub fn writer<T: FrameWriter + Send>(&self, writer: T) -> CaptureFrameWriter<T> {
+        CaptureFrameWriter { writer, capture: self.clone() }
+    }
+}
+
+fn write_records(mut writer: impl Write, receiver: mpsc::Receiver<CaptureRecord>) -> crate::Result<()> {
+    while let Ok(record) = receiver.recv() {
+        record.write(&mut writer)?;
+        for record in receiver.try_iter() {
+            record.write(&mut writer)?;
+        }
+        // Keep the capture complete if the application crashes
+        writer.flush()?;
+    }
+    Ok(())
+}
+
+pub struct CaptureFrameReader<R: FrameReader + Send> {
+    reader: R,
+    capture: Capture,
+}
+#[async_trait]
+impl<R: FrameReader + Send> FrameReader for CaptureFrameReader<R> {
+    async fn receive_frame(&mut self) -> crate::Result<RpcFrame> {
+        let frame = self.reader.receive_frame().await?;
+        self.capture.record(Direction::Received, &frame)?;
+        Ok(frame)
+    }
+}
+
+pub struct CaptureFrameWriter<T: FrameWriter + Send> {
+    writer: T,
+    capture: Capture,
+}
+#[async_trait]
+impl<T: FrameWriter + Send> FrameWriter for CaptureFrameWriter<T> {
+    async fn send_frame(&mut self, frame: RpcFrame) -> crate::Result<()> {
+        self.writer.send_frame(frame.clone()).await?;
+        self.capture.record(Direction::Sent, &frame)
+    }
+}
+
+/// Plays back the frames of one direction from a capture, [`Direction::Received`] by default.
+///
+/// Returns [`Error::UnexpectedEof`] at the end of the capture, like a closed connection.
+pub struct ReplayFrameReader<R: Read + Send> {
+    reader: R,
+    direction: Direction,
+    original_timing: bool,
+    // Timestamp of the previous frame and when it was returned
+    last: Option<(SystemTime, Instant)>,
+    // Record waiting for its time, kept here to make receive_frame() cancel safe
+    pending: Option<CaptureRecord>,
+}
+impl ReplayFrameReader<BufReader<File>> {
+    pub fn open(path: impl AsRef<Path>) -> crate::Result<Self> {
+        Self::new(BufReader::new(File::open(path)?))
+    }
+}
+impl<R: Read + Send> ReplayFrameReader<R> {
+    pub fn new(mut reader: R) -> crate::Result<Self> {
+        let mut magic = [0u8; MAGIC.len()];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(Error::Framing("Not a frame capture".into()));
+        }
+        Ok(Self {
+            reader,
+            direction: Direction::Received,
+            original_timing: false,
+            last: None,
+            pending: None,
+        })
+    }
+    pub fn with_direction(mut self, direction: Direction) -> Self {
+        self.direction = direction;
+        self
+    }
+    /// Frames are returned with the original time spacing instead of as fast as possible.
+    pub fn with_original_timing(mut self, on: bool) -> Self {
+        self.original_timing = on;
+        self
+    }
+    fn next_record(&mut self) -> crate::Result<CaptureRecord> {
+        if let Some(record) = self.pending.take() {
+            return Ok(record)
+        }
+        loop {
+            match CaptureRecord::read(&mut self.reader)? {
+                Some(record) if record.direction == self.direction => { return Ok(record) }
+                Some(_) => { }
+                None => { return Err(Error::UnexpectedEof) }
+            }
+        }
+    }
+}
+#[async_trait]
+impl<R: Read + Send> FrameReader for ReplayFrameReader<R> {
+    async fn receive_frame(&mut self) -> crate::Result<RpcFrame> {
+        let mut record = self.next_record()?;
+        if let Some((last_timestamp, last_instant)) = self.last.filter(|_| self.original_timing) {
+            let delay = record.timestamp.duration_since(last_timestamp).unwrap_or_default();
+            let elapsed = last_instant.elapsed();
+            if delay > elapsed {
+                self.pending = Some(record);
+                Delay::new(delay - elapsed).await;
+                record = self.next_record()?;
+            }
+        }
+        self.last = Some((record.timestamp, Instant::now()));
+        record.frame()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use futures::executor::block_on;
+    use crate::loopback::{self, Encoding};
+    use crate::RpcMessage;
+    use super::*;
+
+    #[test]
+    fn test_capture_and_replay() {
+        let path = std::env::temp_dir().join(format!("shvrpc-capture-{}.cap", std::process::id()));
+        let capture = Capture::create(&path).unwrap();
+        let ((reader, writer), (mut peer_reader, mut peer_writer)) = loopback::connect(Encoding::Frame);
+        let mut reader = capture.reader(reader);
+        let mut writer = capture.writer(writer);
+        let rq = RpcMessage::new_request("test/device", "get", None);
+        let signals: Vec<_> = (1 ..= 3).map(|n| RpcMessage::new_signal("test/device", "chng", Some(n.into()))).collect();
+        block_on(async {
+            writer.send_message(rq.clone()).await.unwrap();
+            peer_reader.receive_frame().await.unwrap();
+            for signal in &signals {
+                peer_writer.send_message(signal.clone()).await.unwrap();
+                reader.receive_frame().await.unwrap();
+            }
+        });
+        drop(reader);
+        drop(writer);
+        capture.close().unwrap();
+
+        let mut replay = ReplayFrameReader::open(&path).unwrap();
+        for signal in &signals {
+            assert_eq!(block_on(replay.receive_message()).unwrap().to_cpon(), signal.to_cpon());
+        }
+        assert!(block_on(replay.receive_frame()).unwrap_err().is_eof());
+
+        let mut replay = ReplayFrameReader::open(&path).unwrap().with_direction(Direction::Sent).with_original_timing(true);
+        assert_eq!(block_on(replay.receive_message()).unwrap().to_cpon(), rq.to_cpon());
+        assert!(ReplayFrameReader::new(&b"garbage!"[..]).is_err());
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_failed_send_not_recorded() {
+        let path = std::env::temp_dir().join(format!("shvrpc-capture-failed-{}.cap", std::process::id()));
+        let capture = Capture::create(&path).unwrap();
+        let ((_reader, writer), peer) = loopback::connect(Encoding::Frame);
+        drop(peer);
+        let mut writer = capture.writer(writer);
+        assert!(block_on(writer.send_message(RpcMessage::new_signal("test/device", "chng", None))).is_err());
+        drop(writer);
+        capture.close().unwrap();
+        let mut replay = ReplayFrameReader::open(&path).unwrap().with_direction(Direction::Sent);
+        assert!(block_on(replay.receive_frame()).unwrap_err().is_eof());
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_replay_timing() {
+        let mut data = MAGIC.to_vec();
+        let start = SystemTime::now();
+        for n in 0 .. 3 {
+            let mut frame = vec![];
+            streamrw::write_frame(&mut frame, RpcMessage::new_signal("test", "chng", Some(n.into())).to_frame().unwrap()).unwrap();
+            CaptureRecord { timestamp: start + Duration::from_millis(50 * n as u64), direction: Direction::Received, data: frame }.write(&mut data).unwrap();
+        }
+        let mut replay = ReplayFrameReader::new(&*data).unwrap();
+        let mut params = vec![];
+        while let Ok(msg) = block_on(replay.receive_message()) {
+            params.push(msg.param().unwrap().as_int());
+        }
+        assert_eq!(params, vec![0, 1, 2]);
+        let mut replay = ReplayFrameReader::new(&*data).unwrap().with_original_timing(true);
+        let start = Instant::now();
+        while block_on(replay.receive_frame()).is_ok() { }
+        let elapsed = start.elapsed().as_millis();
+        assert!(elapsed >= 100, "elapsed: {elapsed} ms");
+    }
+}
@@ -1,13 +1,26 @@
+use std::fmt;
 use std::fs;
 use std::path::Path;
 use std::time::Duration;
 use duration_str::parse;
+#[cfg(feature = "async-std")]
+use futures::{AsyncRead, AsyncReadExt, AsyncWrite};
+#[cfg(feature = "async-std")]
+use futures::io::{BufReader, BufWriter};
 use log::{info};
 use serde::{Deserialize, Serialize};
 use shvproto::RpcValue;
-use crate::{RpcMessage};
+use url::Url;
+use crate::{Error, RpcMessage};
 use crate::framerw::{FrameReader, FrameWriter};
-use crate::util::sha1_password_hash;
+#[cfg(feature = "async-std")]
+use crate::framerw::{BoxedFrameReader, BoxedFrameWriter};
+#[cfg(feature = "async-std")]
+use crate::serialrw::{SerialFrameReader, SerialFrameWriter};
+#[cfg(feature = "async-std")]
+use crate::streamrw::{StreamFrameReader, StreamFrameWriter};
+use crate::redact::REDACTED;
+use crate::util::{sha1_nonce_hash, sha1_password_hash};
 
 #[derive(Copy, Clone, Debug)]
 pub enum LoginType {
@@ -21,17 +34,224 @@ impl LoginType {
             LoginType::SHA1 => "SHA1",
         }
     }
+    // It makes sense to return Option rather than Result as the `FromStr` trait does.
+    #[allow(clippy::should_implement_trait)]
+    pub fn from_str(value: &str) -> Option<Self> {
+        match value {
+            "PLAIN" => Some(LoginType::PLAIN),
+            "SHA1" => Some(LoginType::SHA1),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub enum Password {
+    Plain(String),
+    /// Hex encoded SHA1 hash of the password, as stored by the broker.
+    Sha1(String),
+}
+impl fmt::Debug for Password {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Password::Plain(_) => { "Plain" }
+            Password::Sha1(_) => { "Sha1" }
+        };
+        f.debug_tuple(name).field(&format_args!("{REDACTED}")).finish()
+    }
+}
+impl Default for Password {
+    fn default() -> Self {
+        Password::Plain("".to_string())
+    }
+}
+impl From<&str> for Password {
+    fn from(value: &str) -> Self {
+        Password::Plain(value.to_string())
+    }
+}
+impl From<String> for Password {
+    fn from(value: String) -> Self {
+        Password::Plain(value)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Scheme {
+    Tcp,
+    /// TCP socket with serial framing
+    TcpSerial,
+    LocalSocket,
+    /// Unix socket with serial framing
+    LocalSocketSerial,
+    Serial,
+}
+impl Scheme {
+    // It makes sense to return Option rather than Result as the `FromStr` trait does.
+    #[allow(clippy::should_implement_trait)]
+    pub fn from_str(value: &str) -> Option<Self> {
+        match value {
+            "tcp" => Some(Scheme::Tcp),
+            "tcps" => Some(Scheme::TcpSerial),
+            "unix" => Some(Scheme::LocalSocket),
+            "unixs" => Some(Scheme::LocalSocketSerial),
+            "serial" => Some(Scheme::Serial),
+            _ => None,
+        }
+    }
+    pub fn is_serial_framing(&self) -> bool {
+        !matches!(self, Scheme::Tcp | Scheme::LocalSocket)
+    }
+}
+
+pub const DEFAULT_PORT: u16 = 3755;
+pub const DEFAULT_BAUDRATE: u32 = 115200;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConnectOptions {
+    pub scheme: Scheme,
+    pub with_crc: bool,
+    pub baudrate: u32,
+}
+impl ConnectOptions {
+    /// Query parameters `crc` (`true`/`false`, on by default for `serial:`)
+    /// and `baudrate` are recognized, the others are ignored.
+    pub fn from_url(url: &Url) -> crate::Result<Self> {
+        let scheme = Scheme::from_str(url.scheme())
+            .ok_or_else(|| Error::InvalidUrl(format!("Unsupported URL scheme: {}", url.scheme())))?;
+        let mut options = Self {
+            scheme,
+            with_crc: scheme == Scheme::Serial,
+            baudrate: DEFAULT_BAUDRATE,
+        };
+        for (key, val) in url.query_pairs() {
+            match key.as_ref() {
+                "crc" => {
+                    options.with_crc = match val.as_ref() {
+                        "true" | "1" | "on" => { true }
+                        "false" | "0" | "off" => { false }
+                        _ => { return Err(Error::InvalidUrl(format!("Invalid crc value: {val}"))) }
+                    };
+                }
+                "baudrate" => {
+                    options.baudrate = val.parse().map_err(|_| Error::InvalidUrl(format!("Invalid baudrate: {val}")))?;
+                }
+                _ => {}
+            }
+        }
+        Ok(options)
+    }
+}
+
+/// Opens the transport given by `url` and returns the frame reader/writer pair
+/// using the framing implied by the URL scheme.
+///
+/// * `tcp://host:port`, `tcps://host:port` - TCP socket, default port is 3755
+/// * `unix:/path/to/socket`, `unixs:/path/to/socket` - Unix domain socket
+/// * `serial:/dev/ttyUSB0?baudrate=115200` - serial port
+#[cfg(feature = "async-std")]
+pub async fn connect(url: &Url) -> crate::Result<(BoxedFrameReader, BoxedFrameWriter)> {
+    let options = ConnectOptions::from_url(url)?;
+    info!("Connecting to: {}", crate::util::url_without_credentials(url));
+    match options.scheme {
+        Scheme::Tcp | Scheme::TcpSerial => {
+            let host = url.host_str().unwrap_or("localhost");
+            let port = url.port().unwrap_or(DEFAULT_PORT);
+            let stream = async_std::net::TcpStream::connect((host, port)).await?;
+            let (reader, writer) = stream.split();
+            Ok(frame_reader_writer(reader, writer, &options))
+        }
+        #[cfg(unix)]
+        Scheme::LocalSocket | Scheme::LocalSocketSerial => {
+            let stream = async_std::os::unix::net::UnixStream::connect(url.path()).await?;
+            let (reader, writer) = stream.split();
+            Ok(frame_reader_writer(reader, writer, &options))
+        }
+        #[cfg(unix)]
+        Scheme::Serial => {
+            let port = open_serial_port(url.path(), options.baudrate)?;
+            // Reading and writing must not be serialized by a shared file handle.
+            let writer = async_std::fs::File::from(port.try_clone()?);
+            let reader = async_std::fs::File::from(port);
+            Ok(frame_reader_writer(reader, writer, &options))
+        }
+        #[cfg(not(unix))]
+        Scheme::LocalSocket | Scheme::LocalSocketSerial | Scheme::Serial => {
+            Err(Error::InvalidUrl(format!("URL scheme {} is not supported on this platform", url.scheme())))
+        }
+    }
+}
+
+#[cfg(feature = "async-std")]
+fn frame_reader_writer<R, W>(reader: R, writer: W, options: &ConnectOptions) -> (BoxedFrameReader, BoxedFrameWriter)
+where
+    R: AsyncRead + Unpin + Send + 'static,
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    let reader = BufReader::new(reader);
+    let writer = BufWriter::new(writer);
+    if options.scheme.is_serial_framing() {
+        (
+            Box::new(SerialFrameReader::new(reader).with_crc_check(options.with_crc)),
+            Box::new(SerialFrameWriter::new(writer).with_crc_check(options.with_crc)),
+        )
+    } else {
+        (Box::new(StreamFrameReader::new(reader)), Box::new(StreamFrameWriter::new(writer)))
+    }
 }
 
- pub enum Scheme {
-     Tcp,
-     LocalSocket,
+#[cfg(all(unix, feature = "async-std"))]
+fn open_serial_port(path: &str, baudrate: u32) -> crate::Result<fs::File> {
+    use std::os::unix::fs::OpenOptionsExt;
+    use std::os::unix::io::AsRawFd;
+    let speed = match baudrate {
+        1200 => libc::B1200,
+        2400 => libc::B2400,
+        4800 => libc::B4800,
+        9600 => libc::B9600,
+        19200 => libc::B19200,
+        38400 => libc::B38400,
+        57600 => libc::B57600,
+        115200 => libc::B115200,
+        230400 => libc::B230400,
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        460800 => libc::B460800,
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        921600 => libc::B921600,
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        1000000 => libc::B1000000,
+        _ => { return Err(Error::InvalidUrl(format!("Unsupported baudrate: {baudrate}"))) }
+    };
+    let port = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(libc::O_NOCTTY)
+        .open(path)?;
+    let fd = port.as_raw_fd();
+    // SAFETY: `fd` is a valid open descriptor owned by `port` and `tty` is
+    // initialized by `tcgetattr` before use.
+    unsafe {
+        let mut tty: libc::termios = std::mem::zeroed();
+        if libc::tcgetattr(fd, &mut tty) != 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        libc::cfmakeraw(&mut tty);
+        libc::cfsetispeed(&mut tty, speed);
+        libc::cfsetospeed(&mut tty, speed);
+        tty.c_cflag |= libc::CLOCAL | libc::CREAD;
+        tty.c_cc[libc::VMIN] = 1;
+        tty.c_cc[libc::VTIME] = 0;
+        if libc::tcsetattr(fd, libc::TCSANOW, &tty) != 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+    }
+    Ok(port)
 }
 
 #[derive(Clone, Debug)]
 pub struct LoginParams {
     pub user: String,
-    pub password: String,
+    pub password: Password,
     pub login_type: LoginType,
     pub device_id: String,
     pub mount_point: String,
@@ -43,7 +263,7 @@ impl Default for LoginParams {
     fn default() -> Self {
         LoginParams {
             user: "".to_string(),
-            password: "".to_string(),
+            password: Default::default(),
             login_type: LoginType::SHA1,
             device_id: "".to_string(),
             mount_point: "".to_string(),
@@ -54,11 +274,28 @@ impl Default for LoginParams {
 }
 
 impl LoginParams {
-    pub fn to_rpcvalue(&self) -> RpcValue {
+    pub fn login_password(&self, nonce: &str) -> crate::Result<String> {
+        let password = match (&self.login_type, &self.password) {
+            (LoginType::PLAIN, Password::Plain(password)) => {
+                return Ok(password.clone())
+            }
+            (LoginType::PLAIN, Password::Sha1(_)) => {
+                return Err(Error::Config("PLAIN login type cannot be used with SHA1 hashed password".into()))
+            }
+            (LoginType::SHA1, Password::Plain(password)) => {
+                sha1_password_hash(password.as_bytes(), nonce.as_bytes())
+            }
+            (LoginType::SHA1, Password::Sha1(hash)) => {
+                sha1_nonce_hash(hash.as_bytes(), nonce.as_bytes())
+            }
+        };
+        Ok(String::from_utf8(password)?)
+    }
+    pub fn to_rpcvalue(&self, nonce: &str) -> crate::Result<RpcValue> {
         let mut map = shvproto::Map::new();
         let mut login = shvproto::Map::new();
         login.insert("user".into(), RpcValue::from(&self.user));
-        login.insert("password".into(), RpcValue::from(&self.password));
+        login.insert("password".into(), RpcValue::from(self.login_password(nonce)?));
         login.insert("type".into(), RpcValue::from(self.login_type.to_str()));
         map.insert("login".into(), RpcValue::from(login));
         let mut options = shvproto::Map::new();
@@ -73,11 +310,30 @@ impl LoginParams {
             options.insert("device".into(), RpcValue::from(device));
         }
         map.insert("options".into(), RpcValue::from(options));
-        RpcValue::from(map)
+        Ok(RpcValue::from(map))
     }
 }
 
-pub async fn login(frame_reader: &mut (dyn FrameReader + Send), frame_writer: &mut (dyn FrameWriter + Send), login_params: &LoginParams) -> crate::Result<i32>
+#[derive(Clone, Debug)]
+pub struct LoginResult {
+    result: shvproto::Map,
+}
+impl LoginResult {
+    pub fn from_rpcvalue(result: &RpcValue) -> Self {
+        Self { result: result.as_map().clone() }
+    }
+    pub fn client_id(&self) -> Option<i32> {
+        self.result.get("clientId").map(RpcValue::as_i32)
+    }
+    pub fn get(&self, key: &str) -> Option<&RpcValue> {
+        self.result.get(key)
+    }
+    pub fn as_map(&self) -> &shvproto::Map {
+        &self.result
+    }
+}
+
+pub async fn login(frame_reader: &mut (dyn FrameReader + Send), frame_writer: &mut (dyn FrameWriter + Send), login_params: &LoginParams) -> crate::Result<LoginResult>
 {
     if login_params.reset_session {
         frame_writer.send_reset_session().await?;
@@ -85,21 +341,22 @@ pub async fn login(frame_reader: &mut (dyn FrameReader + Send), frame_writer: &m
     let rq = RpcMessage::new_request("", "hello", None);
     frame_writer.send_message(rq).await?;
     let resp = frame_reader.receive_message().await?;
-    if !resp.is_success() {
-        return Err(resp.error().unwrap().to_rpcvalue().to_cpon().into());
-    }
-    let nonce = resp.result()?.as_map()
-        .get("nonce").ok_or("Bad nonce")?.as_str();
-    let hash = sha1_password_hash(login_params.password.as_bytes(), nonce.as_bytes());
-    let mut login_params = login_params.clone();
-    login_params.password = std::str::from_utf8(&hash)?.into();
-    let rq = RpcMessage::new_request("", "login", Some(login_params.to_rpcvalue()));
+    let rq = login_request(login_params, &resp)?;
     frame_writer.send_message(rq).await?;
     let resp = frame_reader.receive_message().await?;
-    match resp.result()?.as_map().get("clientId") {
-        None => { Ok(0) }
-        Some(client_id) => { Ok(client_id.as_i32()) }
-    }
+    login_result(&resp)
+}
+
+/// Creates the `login` request from the `hello` response, shared by the async and blocking login.
+pub(crate) fn login_request(login_params: &LoginParams, hello_response: &RpcMessage) -> crate::Result<RpcMessage> {
+    let nonce = hello_response.result().map_err(Error::LoginRejected)?.as_map()
+        .get("nonce").ok_or_else(|| Error::InvalidMessage("Nonce missing in hello response".into()))?.as_str();
+    Ok(RpcMessage::new_request("", "login", Some(login_params.to_rpcvalue(nonce)?)))
+}
+
+pub(crate) fn login_result(login_response: &RpcMessage) -> crate::Result<LoginResult> {
+    let result = login_response.result().map_err(Error::LoginRejected)?;
+    Ok(LoginResult::from_rpcvalue(result))
 }
 fn default_heartbeat() -> String { "1m".into() }
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -114,7 +371,7 @@ pub struct ClientConfig {
 impl ClientConfig {
     pub fn from_file(file_name: &str) -> crate::Result<Self> {
         let content = fs::read_to_string(file_name)?;
-        Ok(serde_yaml::from_str(&content)?)
+        serde_yaml::from_str(&content).map_err(|err| Error::Config(err.to_string()))
     }
     pub fn from_file_or_default(file_name: &str, create_if_not_exist: bool) -> crate::Result<Self> {
         let file_path = Path::new(file_name);
@@ -125,11 +382,11 @@ impl ClientConfig {
                     Ok(cfg)
                 }
                 Err(err) => {
-                    Err(format!("Cannot read config file: {file_name} - {err}").into())
+                    Err(Error::Config(format!("Cannot read config file: {file_name} - {err}")))
                 }
             }
         } else if !create_if_not_exist {
-            return Err(format!("Cannot find config file: {file_name}").into())
+            return Err(std::io::Error::new(std::io::ErrorKind::NotFound, format!("Cannot find config file: {file_name}")).into())
         }
         let config = Default::default();
         if create_if_not_exist {
@@ -137,12 +394,24 @@ impl ClientConfig {
                 fs::create_dir_all(config_dir)?;
             }
             info!("Creating default config file: {file_name}");
-            fs::write(file_path, serde_yaml::to_string(&config)?)?;
+            let content = serde_yaml::to_string(&config).map_err(|err| Error::Config(err.to_string()))?;
+            fs::write(file_path, content)?;
         }
         Ok(config)
     }
     pub fn heartbeat_interval_duration(&self) -> crate::Result<std::time::Duration> {
-        Ok(parse(&self.heartbeat_interval)?)
+        parse(&self.heartbeat_interval)
+            .map_err(|err| Error::Config(format!("Invalid heartbeat interval: {} - {err}", self.heartbeat_interval)))
+    }
+    pub fn reconnect_interval_duration(&self) -> crate::Result<Option<std::time::Duration>> {
+        match &self.reconnect_interval {
+            None => { Ok(None) }
+            Some(interval) => {
+                parse(interval)
+                    .map(Some)
+                    .map_err(|err| Error::Config(format!("Invalid reconnect interval: {interval} - {err}")))
+            }
+        }
     }
 }
 impl Default for ClientConfig {
@@ -156,3 +425,74 @@ impl Default for ClientConfig {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use crate::util::sha1_hash;
+    use super::*;
+
+    #[test]
+    fn test_login_password() {
+        let nonce = "1234";
+        let hash = sha1_hash(b"secret");
+        let sha1 = String::from_utf8(sha1_password_hash(b"secret", nonce.as_bytes())).unwrap();
+        for (login_type, password, result) in [
+            (LoginType::PLAIN, Password::from("secret"), Some("secret".to_string())),
+            (LoginType::PLAIN, Password::Sha1(String::from_utf8(hash.clone()).unwrap()), None),
+            (LoginType::SHA1, Password::from("secret"), Some(sha1.clone())),
+            (LoginType::SHA1, Password::Sha1(String::from_utf8(hash.clone()).unwrap()), Some(sha1.clone())),
+        ] {
+            let params = LoginParams { password, login_type, ..Default::default() };
+            assert_eq!(params.login_password(nonce).ok(), result);
+        }
+        let params = LoginParams { password: Password::Sha1(String::from_utf8(hash).unwrap()), login_type: LoginType::PLAIN, ..Default::default() };
+        assert!(matches!(params.login_password(nonce), Err(Error::Config(_))));
+    }
+
+    #[test]
+    fn test_config_intervals() {
+        let config = ClientConfig { heartbeat_interval: "1m".into(), reconnect_interval: Some("soon".into()), ..Default::default() };
+        assert_eq!(config.heartbeat_interval_duration().unwrap(), std::time::Duration::from_secs(60));
+        assert!(matches!(config.reconnect_interval_duration(), Err(Error::Config(_))));
+    }
+
+    #[test]
+    fn test_connect_options() {
+        for (url, scheme, with_crc, baudrate) in [
+            ("tcp://localhost:3755", Scheme::Tcp, false, DEFAULT_BAUDRATE),
+            ("tcps://localhost?crc=true", Scheme::TcpSerial, true, DEFAULT_BAUDRATE),
+            ("unixs:/tmp/shvbroker.sock", Scheme::LocalSocketSerial, false, DEFAULT_BAUDRATE),
+            ("serial:/dev/ttyUSB0", Scheme::Serial, true, DEFAULT_BAUDRATE),
+            ("serial:/dev/ttyUSB0?crc=0&baudrate=9600", Scheme::Serial, false, 9600),
+        ] {
+            let options = ConnectOptions::from_url(&Url::parse(url).unwrap()).unwrap();
+            assert_eq!(options, ConnectOptions { scheme, with_crc, baudrate });
+        }
+        for url in ["http://localhost", "serial:/dev/ttyS0?baudrate=fast", "tcps://localhost?crc=maybe"] {
+            assert!(matches!(ConnectOptions::from_url(&Url::parse(url).unwrap()), Err(Error::InvalidUrl(_))));
+        }
+    }
+
+    #[cfg(feature = "async-std")]
+    #[async_std::test]
+    async fn test_connect_tcp() {
+        let listener = async_std::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = async_std::task::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (reader, writer) = stream.split();
+            let mut reader = crate::streamrw::StreamFrameReader::new(BufReader::new(reader));
+            let mut writer = crate::streamrw::StreamFrameWriter::new(BufWriter::new(writer));
+            let rq = reader.receive_message().await.unwrap();
+            let mut resp = rq.prepare_response().unwrap();
+            resp.set_result("pong");
+            writer.send_message(resp).await.unwrap();
+        });
+        let url = Url::parse(&format!("tcp://127.0.0.1:{port}")).unwrap();
+        let (mut reader, mut writer) = connect(&url).await.unwrap();
+        writer.send_request(".app", "ping", None).await.unwrap();
+        let resp = reader.receive_message().await.unwrap();
+        assert_eq!(resp.result().unwrap().as_str(), "pong");
+        server.await;
+    }
+}
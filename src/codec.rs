@@ -0,0 +1,187 @@
+use bytes::BytesMut;
+use log::*;
+use tokio_util::codec::{Decoder, Encoder};
+use crate::framerw::FrameDecoder;
+use crate::rpcframe::RpcFrame;
+use crate::serialrw::SerialFrameDecoder;
+use crate::streamrw::StreamFrameDecoder;
+use crate::{serialrw, streamrw, Error};
+
+/// Length prefixed framing used on TCP and Unix sockets.
+#[derive(Default)]
+pub struct StreamCodec {
+    decoder: StreamFrameDecoder,
+}
+
+impl StreamCodec {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn with_max_frame_size(mut self, max_frame_size: usize) -> Self {
+        self.decoder = self.decoder.with_max_frame_size(max_frame_size);
+        self
+    }
+}
+impl Decoder for StreamCodec {
+    type Item = RpcFrame;
+    type Error = crate::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> crate::Result<Option<RpcFrame>> {
+        decode(&mut self.decoder, src)
+    }
+    fn decode_eof(&mut self, src: &mut BytesMut) -> crate::Result<Option<RpcFrame>> {
+        decode_eof(&mut self.decoder, src)
+    }
+}
+impl Encoder<RpcFrame> for StreamCodec {
+    type Error = crate::Error;
+
+    fn encode(&mut self, frame: RpcFrame, dst: &mut BytesMut) -> crate::Result<()> {
+        log!(target: "RpcMsg", Level::Debug, "S<== {}", &frame);
+        let mut buff = Vec::new();
+        streamrw::write_frame(&mut buff, frame)?;
+        dst.extend_from_slice(&buff);
+        Ok(())
+    }
+}
+
+/// STX/ETX delimited framing used on serial lines.
+#[derive(Default)]
+pub struct SerialCodec {
+    decoder: SerialFrameDecoder,
+    with_crc: bool,
+}
+
+impl SerialCodec {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn with_crc_check(mut self, on: bool) -> Self {
+        self.decoder = self.decoder.with_crc_check(on);
+        self.with_crc = on;
+        self
+    }
+    pub fn with_max_frame_size(mut self, max_frame_size: usize) -> Self {
+        self.decoder = self.decoder.with_max_frame_size(max_frame_size);
+        self
+    }
+}
+impl Decoder for SerialCodec {
+    type Item = RpcFrame;
+    type Error = crate::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> crate::Result<Option<RpcFrame>> {
+        decode(&mut self.decoder, src)
+    }
+    fn decode_eof(&mut self, src: &mut BytesMut) -> crate::Result<Option<RpcFrame>> {
+        decode_eof(&mut self.decoder, src)
+    }
+}
+impl Encoder<RpcFrame> for SerialCodec {
+    type Error = crate::Error;
+
+    fn encode(&mut self, frame: RpcFrame, dst: &mut BytesMut) -> crate::Result<()> {
+        log!(target: "RpcMsg", Level::Debug, "S<== {}", &frame);
+        let mut buff = Vec::new();
+        serialrw::write_frame(&mut buff, frame, self.with_crc)?;
+        dst.extend_from_slice(&buff);
+        Ok(())
+    }
+}
+
+// The decoders copy the bytes straight into the frame data, nothing is left in `src`
+fn decode(decoder: &mut impl FrameDecoder, src: &mut BytesMut) -> crate::Result<Option<RpcFrame>> {
+    if !src.is_empty() {
+        decoder.push(src);
+        src.clear();
+    }
+    decoder.next_frame()
+}
+
+fn decode_eof(decoder: &mut impl FrameDecoder, src: &mut BytesMut) -> crate::Result<Option<RpcFrame>> {
+    match decode(decoder, src)? {
+        Some(frame) => { Ok(Some(frame)) }
+        None if decoder.has_partial_frame() => { Err(Error::UnexpectedEof) }
+        None => { Ok(None) }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use shvproto::RpcValue;
+    use crate::RpcMessage;
+    use crate::serialrw::{ATX, ETX, STX};
+    use super::*;
+
+    fn decode_all<D: Decoder<Item = RpcFrame, Error = crate::Error>>(decoder: &mut D, data: &[u8], chunk_size: usize) -> Vec<RpcFrame> {
+        let mut frames = vec![];
+        let mut src = BytesMut::new();
+        for chunk in data.chunks(chunk_size) {
+            src.extend_from_slice(chunk);
+            while let Some(frame) = decoder.decode(&mut src).unwrap() {
+                frames.push(frame);
+            }
+        }
+        frames
+    }
+
+    #[test]
+    fn test_codec_round_trip() {
+        let frames: Vec<_> = [
+            RpcMessage::new_request("foo/bar", "baz", Some("hello".into())),
+            RpcMessage::new_request("test", "ping", Some(RpcValue::from(vec![STX, ETX, ATX, serialrw::ESC]))),
+            RpcMessage::new_signal("test", "chng", Some(42.into())),
+        ].iter().map(|msg| msg.to_frame().unwrap()).collect();
+        for chunk_size in [1, 3, 1000] {
+            let mut buff = BytesMut::new();
+            for frame in &frames {
+                StreamCodec::new().encode(frame.clone(), &mut buff).unwrap();
+            }
+            assert_eq!(decode_all(&mut StreamCodec::new(), &buff, chunk_size), frames);
+            for with_crc in [false, true] {
+                let mut codec = SerialCodec::new().with_crc_check(with_crc);
+                let mut buff = BytesMut::from(&b"garbage"[..]);
+                for frame in &frames {
+                    buff.extend_from_slice(&[STX, 1, 2, ATX]);
+                    codec.encode(frame.clone(), &mut buff).unwrap();
+                }
+                assert_eq!(decode_all(&mut codec, &buff, chunk_size), frames);
+            }
+        }
+    }
+
+    #[test]
+    fn test_truncated_frame_at_eof() {
+        let frame = RpcMessage::new_request("foo/bar", "baz", Some("hello".into())).to_frame().unwrap();
+        let mut stream_buff = BytesMut::new();
+        StreamCodec::new().encode(frame.clone(), &mut stream_buff).unwrap();
+        let mut serial_buff = BytesMut::from(&b"garbage"[..]);
+        SerialCodec::new().encode(frame.clone(), &mut serial_buff).unwrap();
+        let codecs: [(&mut dyn Decoder<Item = RpcFrame, Error = crate::Error>, BytesMut); 2] = [
+            (&mut StreamCodec::new(), stream_buff),
+            (&mut SerialCodec::new(), serial_buff),
+        ];
+        for (codec, buff) in codecs {
+            let mut src = buff.clone();
+            assert_eq!(codec.decode_eof(&mut src).unwrap(), Some(frame.clone()));
+            assert_eq!(codec.decode_eof(&mut src).unwrap(), None);
+            let mut src = BytesMut::from(&buff[.. buff.len() - 1]);
+            assert!(codec.decode(&mut src).unwrap().is_none());
+            assert!(codec.decode_eof(&mut src).unwrap_err().is_eof());
+        }
+    }
+
+    #[test]
+    fn test_serial_codec_drops_bad_crc() {
+        let frame = RpcMessage::new_request("foo/bar", "baz", Some("hello".into())).to_frame().unwrap();
+        let mut codec = SerialCodec::new().with_crc_check(true);
+        let mut buff = BytesMut::new();
+        codec.encode(frame.clone(), &mut buff).unwrap();
+        // Corrupt a plain data byte, the CRC bytes depend on the request ID and might be escaped
+        let mut corrupted = buff.clone();
+        let pos = corrupted.windows(5).position(|w| w == b"hello").unwrap();
+        corrupted[pos] = b'j';
+        corrupted.extend_from_slice(&buff);
+        assert_eq!(decode_all(&mut codec, &corrupted, 1000), vec![frame]);
+    }
+}
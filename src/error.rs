@@ -0,0 +1,124 @@
+use std::fmt;
+use std::io;
+use shvproto::ReadError;
+use crate::rpcmessage::RpcError;
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    /// The peer closed the connection.
+    UnexpectedEof,
+    Framing(String),
+    FrameTooLarge { size: usize, max_size: usize },
+    UnsupportedProtocol(u8),
+    /// The peer requested a session reset, see [`RpcFrame::is_reset_session`](crate::rpcframe::RpcFrame::is_reset_session).
+    ResetSession,
+    BadMeta(String),
+    /// The payload cannot be decoded or it is not a valid RPC message.
+    InvalidMessage(String),
+    Decode(ReadError),
+    /// Error response received from the peer.
+    Rpc(RpcError),
+    LoginRejected(RpcError),
+    /// Connection URL is malformed or it is not supported.
+    InvalidUrl(String),
+    /// Invalid configuration, like an unreadable config file or login parameters that cannot be used together.
+    Config(String),
+    /// Subscription path or method pattern is not a valid glob pattern.
+    InvalidPattern(glob::PatternError),
+    /// Heartbeat pings were not answered, the connection is considered dead.
+    HeartbeatTimeout { missed: u32 },
+    Other(Box<dyn std::error::Error + Send + Sync>),
+}
+impl Error {
+    pub fn is_eof(&self) -> bool {
+        match self {
+            Error::UnexpectedEof => { true }
+            Error::Io(err) => { err.kind() == io::ErrorKind::UnexpectedEof }
+            _ => { false }
+        }
+    }
+}
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(err) => { write!(f, "IO error: {err}") }
+            Error::UnexpectedEof => { write!(f, "Unexpected end of stream") }
+            Error::Framing(msg) => { write!(f, "Framing error: {msg}") }
+            Error::FrameTooLarge { size, max_size } => { write!(f, "Frame size {size} exceeds the limit of {max_size} bytes") }
+            Error::UnsupportedProtocol(protocol) => { write!(f, "Unsupported protocol: {protocol}") }
+            Error::ResetSession => { write!(f, "Reset session received") }
+            Error::BadMeta(msg) => { write!(f, "Meta data read error: {msg}") }
+            Error::InvalidMessage(msg) => { write!(f, "Invalid RPC message: {msg}") }
+            Error::Decode(err) => { write!(f, "Decode error: {err}") }
+            Error::Rpc(err) => { write!(f, "RPC error: {err}") }
+            Error::LoginRejected(err) => { write!(f, "Login rejected: {err}") }
+            Error::InvalidUrl(msg) => { write!(f, "Invalid URL: {msg}") }
+            Error::Config(msg) => { write!(f, "Invalid configuration: {msg}") }
+            Error::InvalidPattern(err) => { write!(f, "Invalid pattern: {err}") }
+            Error::HeartbeatTimeout { missed } => { write!(f, "Connection is dead, {missed} heartbeat pings not answered") }
+            Error::Other(err) => { write!(f, "{err}") }
+        }
+    }
+}
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(err) => { Some(err) }
+            Error::Decode(err) => { Some(err) }
+            Error::Rpc(err) | Error::LoginRejected(err) => { Some(err) }
+            Error::InvalidPattern(err) => { Some(err) }
+            Error::Other(err) => { Some(err.as_ref()) }
+            _ => { None }
+        }
+    }
+}
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        if err.kind() == io::ErrorKind::UnexpectedEof {
+            Error::UnexpectedEof
+        } else {
+            Error::Io(err)
+        }
+    }
+}
+impl From<ReadError> for Error {
+    fn from(err: ReadError) -> Self {
+        Error::Decode(err)
+    }
+}
+impl From<RpcError> for Error {
+    fn from(err: RpcError) -> Self {
+        Error::Rpc(err)
+    }
+}
+impl From<Box<dyn std::error::Error + Send + Sync>> for Error {
+    fn from(err: Box<dyn std::error::Error + Send + Sync>) -> Self {
+        Error::Other(err)
+    }
+}
+impl From<std::string::FromUtf8Error> for Error {
+    fn from(err: std::string::FromUtf8Error) -> Self {
+        Error::Other(err.into())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::error::Error as _;
+    use crate::rpcmessage::RpcErrorCode;
+    use super::*;
+
+    #[test]
+    fn test_error_conversion() {
+        let err = Error::from(io::Error::from(io::ErrorKind::UnexpectedEof));
+        assert!(matches!(err, Error::UnexpectedEof));
+        assert!(err.is_eof());
+        let err = Error::from(io::Error::from(io::ErrorKind::ConnectionReset));
+        assert!(!err.is_eof());
+        assert!(err.source().is_some());
+        let err = Error::LoginRejected(RpcError::new(RpcErrorCode::PermissionDenied, "Invalid login"));
+        let source = err.source().and_then(|err| err.downcast_ref::<RpcError>()).unwrap();
+        assert_eq!(source.code, RpcErrorCode::PermissionDenied);
+    }
+}
@@ -1,17 +1,83 @@
+use std::pin::Pin;
 use async_trait::async_trait;
+use futures::stream::BoxStream;
+use futures::{sink, stream, Sink, SinkExt, StreamExt};
 use crate::rpcframe::{Protocol, RpcFrame};
-use shvproto::{ChainPackWriter, MetaMap, RpcValue, Writer};
-use crate::{RpcMessage, RpcMessageMetaTags};
+use shvproto::{ChainPackReader, ChainPackWriter, CponReader, CponWriter, MetaMap, Reader, RpcValue, Writer};
+use crate::{Error, json, RpcMessage, RpcMessageMetaTags};
 use crate::rpcmessage::{RpcError, RpcErrorCode, RqId};
 #[async_trait]
 pub trait FrameReader {
     async fn receive_frame(&mut self) -> crate::Result<RpcFrame>;
 
+    /// Returns [`Error::ResetSession`] when the peer requests a session reset.
     async fn receive_message(&mut self) -> crate::Result<RpcMessage> {
         let frame = self.receive_frame().await?;
         let msg = frame.to_rpcmesage()?;
         Ok(msg)
     }
+
+    /// Converts the reader into a stream of received frames.
+    ///
+    /// The stream ends when the peer closes the connection. Errors of a single frame,
+    /// like an oversized frame or invalid message, are yielded and reading continues.
+    /// Any other error, like IO or framing error, is yielded as the last item.
+    fn into_stream(self) -> BoxStream<'static, crate::Result<RpcFrame>>
+    where
+        Self: Sized + Send + 'static,
+    {
+        stream::unfold(Some(self), |reader| async move {
+            let mut reader = reader?;
+            match reader.receive_frame().await {
+                Ok(frame) => { Some((Ok(frame), Some(reader))) }
+                Err(err) if err.is_eof() => { None }
+                // The frame was consumed, the next one can be read
+                Err(err @ (Error::FrameTooLarge { .. } | Error::BadMeta(_) | Error::InvalidMessage(_) | Error::Decode(_) | Error::UnsupportedProtocol(_))) => {
+                    Some((Err(err), Some(reader)))
+                }
+                Err(err) => { Some((Err(err), None)) }
+            }
+        }).boxed()
+    }
+    /// Converts the reader into a stream of received messages, see [`FrameReader::into_stream`].
+    fn into_message_stream(self) -> BoxStream<'static, crate::Result<RpcMessage>>
+    where
+        Self: Sized + Send + 'static,
+    {
+        self.into_stream()
+            .map(|frame| frame.and_then(|frame| frame.to_rpcmesage()))
+            .boxed()
+    }
+}
+
+pub type FrameSink = Pin<Box<dyn Sink<RpcFrame, Error = Error> + Send>>;
+pub type MessageSink = Pin<Box<dyn Sink<RpcMessage, Error = Error> + Send>>;
+
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 64 * 1024 * 1024;
+
+/// Incremental frame decoder independent of any IO model.
+///
+/// Received bytes, in chunks of any size, are passed to [`FrameDecoder::push`]
+/// and complete frames are then taken out by [`FrameDecoder::next_frame`].
+pub trait FrameDecoder {
+    fn push(&mut self, data: &[u8]);
+    /// Returns `Ok(None)` if more data is needed to complete the next frame.
+    fn next_frame(&mut self) -> crate::Result<Option<RpcFrame>>;
+    /// Returns `true` if a part of an incomplete frame has been pushed.
+    fn has_partial_frame(&self) -> bool;
+}
+
+pub type BoxedFrameReader = Box<dyn FrameReader + Send>;
+pub type BoxedFrameWriter = Box<dyn FrameWriter + Send>;
+
+#[async_trait]
+impl<T: FrameReader + Send + ?Sized> FrameReader for Box<T> {
+    async fn receive_frame(&mut self) -> crate::Result<RpcFrame> {
+        (**self).receive_frame().await
+    }
+    async fn receive_message(&mut self) -> crate::Result<RpcMessage> {
+        (**self).receive_message().await
+    }
 }
 
 #[async_trait]
@@ -44,11 +110,38 @@ pub trait FrameWriter {
         self.send_message(rpcmsg).await?;
         Ok(rqid)
     }
+
+    /// Converts the writer into a sink of frames, every frame is flushed when sent.
+    fn into_sink(self) -> FrameSink
+    where
+        Self: Sized + Send + 'static,
+    {
+        Box::pin(sink::unfold(self, |mut writer, frame: RpcFrame| async move {
+            writer.send_frame(frame).await?;
+            Ok(writer)
+        }))
+    }
+    fn into_message_sink(self) -> MessageSink
+    where
+        Self: Sized + Send + 'static,
+    {
+        Box::pin(self.into_sink().with(|msg: RpcMessage| futures::future::ready(msg.to_frame())))
+    }
+}
+
+#[async_trait]
+impl<T: FrameWriter + Send + ?Sized> FrameWriter for Box<T> {
+    async fn send_frame(&mut self, frame: RpcFrame) -> crate::Result<()> {
+        (**self).send_frame(frame).await
+    }
 }
 
+/// Serializes the frame meta as it is written on the wire.
+///
+/// JSON frames have no separate meta part, see [`serialize_frame`].
 pub fn serialize_meta(frame: &RpcFrame) -> crate::Result<Vec<u8>> {
     let data = match frame.protocol {
-        Protocol::ResetSession => {
+        Protocol::ResetSession | Protocol::Json | Protocol::Unknown(_) => {
             Vec::new()
         }
         Protocol::ChainPack => {
@@ -57,7 +150,128 @@ pub fn serialize_meta(frame: &RpcFrame) -> crate::Result<Vec<u8>> {
             wr.write_meta(&frame.meta)?;
             data
         }
+        Protocol::Cpon => {
+            let mut data: Vec<u8> = Vec::new();
+            let mut wr = CponWriter::new(&mut data);
+            wr.write_meta(&frame.meta)?;
+            data
+        }
     };
     Ok(data)
 }
 
+/// Serializes the frame protocol byte, meta and data, i.e. the frame without the length prefix or STX/ETX.
+pub fn serialize_frame(frame: &RpcFrame) -> crate::Result<Vec<u8>> {
+    let mut data = vec![frame.protocol.into()];
+    match frame.protocol {
+        Protocol::ResetSession => {}
+        Protocol::Json => {
+            // Meta members are merged into the message object
+            let mut object = json::parse_object(&frame.data)?;
+            json::meta_to_json(&frame.meta, &mut object);
+            serde_json::to_writer(&mut data, &object).map_err(|err| Error::InvalidMessage(err.to_string()))?;
+        }
+        _ => {
+            data.extend_from_slice(&serialize_meta(frame)?);
+            data.extend_from_slice(&frame.data);
+        }
+    }
+    Ok(data)
+}
+
+/// Parses frame data following the length prefix or STX, i.e. the protocol byte, meta and message data.
+pub(crate) fn parse_frame_data(mut data: Vec<u8>) -> crate::Result<RpcFrame> {
+    let protocol = Protocol::from(data.first().copied().ok_or_else(|| Error::Framing("Empty frame".into()))?);
+    match protocol {
+        Protocol::ResetSession => {
+            Ok(RpcFrame { protocol, meta: MetaMap::new(), data: vec![] })
+        }
+        Protocol::ChainPack => {
+            let mut buffrd = &data[1 ..];
+            let mut rd = ChainPackReader::new(&mut buffrd);
+            match rd.try_read_meta() {
+                Ok(Some(meta)) => {
+                    let pos = rd.position() + 1;
+                    let data: Vec<_> = data.drain(pos .. ).collect();
+                    Ok(RpcFrame { protocol, meta, data })
+                }
+                Ok(None) => { Err(Error::BadMeta("Meta data missing".into())) }
+                Err(err) => { Err(Error::BadMeta(err.to_string())) }
+            }
+        }
+        Protocol::Cpon => {
+            let mut buffrd = &data[1 ..];
+            let mut rd = CponReader::new(&mut buffrd);
+            let rv = rd.read().map_err(|err| Error::BadMeta(err.to_string()))?;
+            if rv.meta().is_empty() {
+                return Err(Error::BadMeta("Meta data missing".into()));
+            }
+            let mut data = Vec::new();
+            let mut wr = CponWriter::new(&mut data);
+            wr.write_value(rv.value())?;
+            Ok(RpcFrame { protocol, meta: rv.meta().clone(), data })
+        }
+        Protocol::Json => {
+            let object = json::parse_object(&data[1 ..])?;
+            let meta = json::meta_from_json(&object);
+            let body = json::body_to_json(&json::body_from_json(&object)?);
+            let data = serde_json::to_vec(&body).map_err(|err| Error::InvalidMessage(err.to_string()))?;
+            Ok(RpcFrame { protocol, meta, data })
+        }
+        Protocol::Unknown(_) => {
+            data.remove(0);
+            Ok(RpcFrame { protocol, meta: MetaMap::new(), data })
+        }
+    }
+}
+
+#[cfg(all(test, feature = "async-std"))]
+mod test {
+    use async_std::net::{TcpListener, TcpStream};
+    use futures::io::Cursor;
+    use futures::AsyncReadExt;
+    use crate::streamrw::{self, StreamFrameReader, StreamFrameWriter};
+    use super::*;
+
+    #[async_std::test]
+    async fn test_stream_and_sink() {
+        let msgs: Vec<_> = (1 ..= 3).map(|n| RpcMessage::new_signal("test", "chng", Some(n.into()))).collect();
+        let mut data = vec![];
+        for msg in &msgs {
+            streamrw::write_frame(&mut data, msg.to_frame().unwrap()).unwrap();
+        }
+        let source = StreamFrameReader::new(Cursor::new(data)).into_stream();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (peer, _) = listener.accept().await.unwrap();
+        let (_, writer) = stream.split();
+        let (reader, _) = peer.split();
+        let forward = async_std::task::spawn(source.forward(StreamFrameWriter::new(writer).into_sink()));
+        let received: Vec<_> = StreamFrameReader::new(reader).into_message_stream()
+            .take(msgs.len())
+            .map(|msg| msg.unwrap().to_cpon())
+            .collect().await;
+        assert_eq!(received, msgs.iter().map(RpcMessage::to_cpon).collect::<Vec<_>>());
+        // the source stream ends on EOF
+        forward.await.unwrap();
+    }
+
+    #[async_std::test]
+    async fn test_stream_errors() {
+        let small = RpcMessage::new_signal("test", "chng", Some(1.into())).to_frame().unwrap();
+        let large = RpcMessage::new_signal("test", "chng", Some(RpcValue::from(vec![0u8; 1000]))).to_frame().unwrap();
+        let mut data = vec![];
+        for frame in [&large, &small] {
+            streamrw::write_frame(&mut data, frame.clone()).unwrap();
+        }
+        // corrupt length prefix
+        data.extend_from_slice(&[0xff, 1, 2, 3]);
+        streamrw::write_frame(&mut data, small.clone()).unwrap();
+        let received: Vec<_> = StreamFrameReader::new(Cursor::new(data)).with_max_frame_size(100).into_stream().collect().await;
+        assert_eq!(received.len(), 3, "{received:?}");
+        assert!(matches!(received[0], Err(Error::FrameTooLarge { .. })));
+        assert_eq!(received[1].as_ref().unwrap(), &small);
+        assert!(matches!(received[2], Err(Error::Framing(_))));
+    }
+}
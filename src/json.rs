@@ -0,0 +1,173 @@
+//! JSON encoding of RPC messages.
+//!
+//! A message is encoded as a single JSON-RPC like object, the well known meta tags
+//! and message keys are mapped to named members:
+//!
+//! ```json
+//! {"id": 4, "shvPath": "test/device", "method": "get", "callerIds": [1, 2], "params": 42}
+//! ```
+//!
+//! JSON cannot represent all the `RpcValue` types, blobs are encoded as hex strings,
+//! `DateTime` and `Decimal` values as CPON strings. Meta tags not listed below are dropped.
+use shvproto::{IMap, List, Map, MetaMap, RpcValue, Value};
+use crate::Error;
+use crate::rpcmessage::{Key, RpcError, RpcErrorCode, Tag};
+use crate::rpctype;
+
+const META_KEYS: [(Tag, &str); 8] = [
+    (Tag::RequestId, "id"),
+    (Tag::ShvPath, "shvPath"),
+    (Tag::Method, "method"),
+    (Tag::CallerIds, "callerIds"),
+    (Tag::RevCallerIds, "revCallerIds"),
+    (Tag::Access, "access"),
+    (Tag::UserId, "userId"),
+    (Tag::AccessLevel, "accessLevel"),
+];
+const BODY_KEYS: [(Key, &str); 3] = [
+    (Key::Params, "params"),
+    (Key::Result, "result"),
+    (Key::Error, "error"),
+];
+const ERROR_CODE: &str = "code";
+const ERROR_MESSAGE: &str = "message";
+
+pub fn rpcvalue_to_json(rv: &RpcValue) -> serde_json::Value {
+    match rv.value() {
+        Value::Null => { serde_json::Value::Null }
+        Value::Int(n) => { (*n).into() }
+        Value::UInt(n) => { (*n).into() }
+        Value::Double(n) => { serde_json::Number::from_f64(*n).map_or(serde_json::Value::Null, serde_json::Value::Number) }
+        Value::Bool(b) => { (*b).into() }
+        Value::String(s) => { s.as_str().into() }
+        Value::Blob(b) => { hex::encode(b.as_slice()).into() }
+        Value::DateTime(_) | Value::Decimal(_) => { rv.to_cpon().into() }
+        Value::List(list) => {
+            list.iter().map(rpcvalue_to_json).collect::<Vec<_>>().into()
+        }
+        Value::Map(map) => {
+            map.iter().map(|(key, val)| (key.clone(), rpcvalue_to_json(val))).collect::<serde_json::Map<_, _>>().into()
+        }
+        Value::IMap(map) => {
+            map.iter().map(|(key, val)| (key.to_string(), rpcvalue_to_json(val))).collect::<serde_json::Map<_, _>>().into()
+        }
+    }
+}
+
+pub fn rpcvalue_from_json(value: &serde_json::Value) -> RpcValue {
+    match value {
+        serde_json::Value::Null => { RpcValue::new(Value::Null, None) }
+        serde_json::Value::Bool(b) => { RpcValue::from(*b) }
+        serde_json::Value::Number(n) => {
+            if let Some(n) = n.as_i64() {
+                RpcValue::from(n)
+            } else if let Some(n) = n.as_u64() {
+                RpcValue::from(n)
+            } else {
+                RpcValue::from(n.as_f64().unwrap_or_default())
+            }
+        }
+        serde_json::Value::String(s) => { RpcValue::from(s.as_str()) }
+        serde_json::Value::Array(array) => {
+            RpcValue::from(array.iter().map(rpcvalue_from_json).collect::<List>())
+        }
+        serde_json::Value::Object(object) => {
+            RpcValue::from(object.iter().map(|(key, val)| (key.clone(), rpcvalue_from_json(val))).collect::<Map>())
+        }
+    }
+}
+
+pub(crate) fn meta_to_json(meta: &MetaMap, object: &mut serde_json::Map<String, serde_json::Value>) {
+    for (tag, name) in META_KEYS {
+        if let Some(val) = meta.get(tag as i32) {
+            object.insert(name.to_string(), rpcvalue_to_json(val));
+        }
+    }
+}
+
+pub(crate) fn meta_from_json(object: &serde_json::Map<String, serde_json::Value>) -> MetaMap {
+    let mut meta = MetaMap::new();
+    meta.insert(rpctype::Tag::MetaTypeId as i32, RpcValue::from(rpctype::GlobalNS::MetaTypeID::ChainPackRpcMessage as i32));
+    for (tag, name) in META_KEYS {
+        if let Some(val) = object.get(name) {
+            meta.insert(tag as i32, rpcvalue_from_json(val));
+        }
+    }
+    meta
+}
+
+pub(crate) fn body_to_json(body: &RpcValue) -> serde_json::Map<String, serde_json::Value> {
+    let mut object = serde_json::Map::new();
+    for (key, name) in BODY_KEYS {
+        let key = key as i32;
+        let Some(val) = body.as_imap().get(&key) else { continue };
+        let val = if key == Key::Error as i32 {
+            let err = RpcError::from_rpcvalue(val).unwrap_or_default();
+            let mut error = serde_json::Map::new();
+            error.insert(ERROR_CODE.to_string(), (err.code as i32).into());
+            error.insert(ERROR_MESSAGE.to_string(), err.message.into());
+            error.into()
+        } else {
+            rpcvalue_to_json(val)
+        };
+        object.insert(name.to_string(), val);
+    }
+    object
+}
+
+pub(crate) fn body_from_json(object: &serde_json::Map<String, serde_json::Value>) -> crate::Result<RpcValue> {
+    let mut body = IMap::new();
+    for (key, name) in BODY_KEYS {
+        let key = key as i32;
+        let Some(val) = object.get(name) else { continue };
+        let val = if key == Key::Error as i32 {
+            let error = val.as_object().ok_or_else(|| Error::InvalidMessage("JSON error must be an object".into()))?;
+            let code = error.get(ERROR_CODE).and_then(serde_json::Value::as_i64).unwrap_or(RpcErrorCode::Unknown as i64);
+            let message = error.get(ERROR_MESSAGE).and_then(serde_json::Value::as_str).unwrap_or_default();
+            RpcError {
+                code: i32::try_from(code).ok().and_then(|code| code.try_into().ok()).unwrap_or(RpcErrorCode::Unknown),
+                message: message.to_string(),
+            }.to_rpcvalue()
+        } else {
+            rpcvalue_from_json(val)
+        };
+        body.insert(key, val);
+    }
+    Ok(RpcValue::from(body))
+}
+
+pub(crate) fn parse_object(data: &[u8]) -> crate::Result<serde_json::Map<String, serde_json::Value>> {
+    match serde_json::from_slice(data) {
+        Ok(serde_json::Value::Object(object)) => { Ok(object) }
+        Ok(_) => { Err(Error::InvalidMessage("JSON message must be an object".into())) }
+        Err(err) => { Err(Error::InvalidMessage(format!("JSON parse error: {err}"))) }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{RpcMessage, RpcMessageMetaTags};
+    use crate::rpcframe::Protocol;
+    use super::*;
+
+    #[test]
+    fn test_json_message() {
+        let mut rq = RpcMessage::new_request("test/device", "set", Some(RpcValue::from(vec![RpcValue::from(1), RpcValue::from("foo")])));
+        rq.set_caller_ids(&[1, 2]);
+        let frame = rq.to_frame_with_protocol(Protocol::Json).unwrap();
+        let object = parse_object(&crate::framerw::serialize_frame(&frame).unwrap()[1 ..]).unwrap();
+        assert_eq!(object.get("method"), Some(&"set".into()));
+        assert_eq!(object.get("shvPath"), Some(&"test/device".into()));
+        assert_eq!(object.get("callerIds"), Some(&serde_json::json!([1, 2])));
+        assert_eq!(object.get("params"), Some(&serde_json::json!([1, "foo"])));
+        let msg = frame.to_rpcmesage().unwrap();
+        assert_eq!(msg.to_cpon(), rq.to_cpon());
+
+        let mut resp = rq.prepare_response().unwrap();
+        resp.set_error(RpcError::new(RpcErrorCode::MethodNotFound, "not found"));
+        let msg = resp.to_frame_with_protocol(Protocol::Json).unwrap().to_rpcmesage().unwrap();
+        let err = msg.error().unwrap();
+        assert_eq!(err.code, RpcErrorCode::MethodNotFound);
+        assert_eq!(err.message, "not found");
+    }
+}
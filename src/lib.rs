@@ -1,16 +1,31 @@
 pub mod framerw;
+pub mod blocking;
+pub mod capture;
 pub mod client;
+#[cfg(feature = "tokio")]
+pub mod codec;
+pub mod error;
+pub mod json;
+pub mod loopback;
 pub mod metamethod;
+#[cfg(feature = "async-std")]
+pub mod mockbroker;
+pub mod reconnect;
+pub mod redact;
 pub mod rpc;
+pub mod rpcclient;
 pub mod rpctype;
 pub mod rpcframe;
 pub mod rpcmessage;
 pub mod serialrw;
+pub mod server;
 pub mod streamrw;
+#[cfg(all(test, feature = "async-std"))]
+mod testutil;
 pub mod util;
 
 pub use rpcmessage::{RpcMessage, RpcMessageMetaTags};
 
-pub type Error = Box<dyn std::error::Error + Send + Sync>;
+pub use error::Error;
 pub type Result<T> = std::result::Result<T, Error>;
 
@@ -0,0 +1,171 @@
+//! In-memory duplex transport for tests.
+//!
+//! [`connect`] returns two connected reader/writer pairs, frames written by one side
+//! are received by the other one.
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use async_trait::async_trait;
+use futures::channel::mpsc;
+use futures::{AsyncRead, AsyncWrite, StreamExt};
+use crate::framerw::{FrameReader, FrameWriter};
+use crate::reconnect::Connection;
+use crate::rpcframe::RpcFrame;
+use crate::serialrw::{SerialFrameReader, SerialFrameWriter};
+use crate::streamrw::{StreamFrameReader, StreamFrameWriter};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Encoding {
+    /// Frames are passed as they are, without serialization.
+    Frame,
+    /// Frames go through the length prefixed stream encoding.
+    Stream,
+    /// Frames go through the serial encoding.
+    Serial { with_crc: bool },
+}
+
+pub struct ChannelFrameReader(mpsc::UnboundedReceiver<RpcFrame>);
+#[async_trait]
+impl FrameReader for ChannelFrameReader {
+    async fn receive_frame(&mut self) -> crate::Result<RpcFrame> {
+        self.0.next().await.ok_or(crate::Error::UnexpectedEof)
+    }
+}
+
+pub struct ChannelFrameWriter(mpsc::UnboundedSender<RpcFrame>);
+#[async_trait]
+impl FrameWriter for ChannelFrameWriter {
+    async fn send_frame(&mut self, frame: RpcFrame) -> crate::Result<()> {
+        Ok(self.0.unbounded_send(frame).map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?)
+    }
+}
+
+/// Read half of an in-memory byte pipe, reading returns EOF when the write half is dropped.
+pub struct PipeReader {
+    receiver: mpsc::UnboundedReceiver<Vec<u8>>,
+    chunk: Vec<u8>,
+    pos: usize,
+}
+impl AsyncRead for PipeReader {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        while self.pos == self.chunk.len() {
+            match self.receiver.poll_next_unpin(cx) {
+                Poll::Ready(Some(chunk)) => {
+                    self.chunk = chunk;
+                    self.pos = 0;
+                }
+                Poll::Ready(None) => { return Poll::Ready(Ok(0)) }
+                Poll::Pending => { return Poll::Pending }
+            }
+        }
+        let n = std::cmp::min(buf.len(), self.chunk.len() - self.pos);
+        buf[.. n].copy_from_slice(&self.chunk[self.pos .. self.pos + n]);
+        self.pos += n;
+        Poll::Ready(Ok(n))
+    }
+}
+
+/// Write half of an in-memory byte pipe.
+pub struct PipeWriter(mpsc::UnboundedSender<Vec<u8>>);
+impl AsyncWrite for PipeWriter {
+    fn poll_write(self: Pin<&mut Self>, _cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self.0.unbounded_send(buf.to_vec()) {
+            Ok(()) => { Poll::Ready(Ok(buf.len())) }
+            Err(_) => { Poll::Ready(Err(io::ErrorKind::BrokenPipe.into())) }
+        }
+    }
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.0.close_channel();
+        Poll::Ready(Ok(()))
+    }
+}
+
+pub fn pipe() -> (PipeReader, PipeWriter) {
+    let (sender, receiver) = mpsc::unbounded();
+    (PipeReader { receiver, chunk: vec![], pos: 0 }, PipeWriter(sender))
+}
+
+/// Returns two connected reader/writer pairs.
+pub fn connect(encoding: Encoding) -> (Connection, Connection) {
+    match encoding {
+        Encoding::Frame => {
+            let (tx1, rx1) = mpsc::unbounded();
+            let (tx2, rx2) = mpsc::unbounded();
+            (
+                (Box::new(ChannelFrameReader(rx2)), Box::new(ChannelFrameWriter(tx1))),
+                (Box::new(ChannelFrameReader(rx1)), Box::new(ChannelFrameWriter(tx2))),
+            )
+        }
+        Encoding::Stream => {
+            let (reader1, writer2) = pipe();
+            let (reader2, writer1) = pipe();
+            (
+                (Box::new(StreamFrameReader::new(reader1)), Box::new(StreamFrameWriter::new(writer1))),
+                (Box::new(StreamFrameReader::new(reader2)), Box::new(StreamFrameWriter::new(writer2))),
+            )
+        }
+        Encoding::Serial { with_crc } => {
+            let (reader1, writer2) = pipe();
+            let (reader2, writer1) = pipe();
+            (
+                (Box::new(SerialFrameReader::new(reader1).with_crc_check(with_crc)), Box::new(SerialFrameWriter::new(writer1).with_crc_check(with_crc))),
+                (Box::new(SerialFrameReader::new(reader2).with_crc_check(with_crc)), Box::new(SerialFrameWriter::new(writer2).with_crc_check(with_crc))),
+            )
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use futures::executor::block_on;
+    use futures::{AsyncReadExt, AsyncWriteExt};
+    use crate::client::{self, LoginParams, Password};
+    use crate::server;
+    use crate::RpcMessageMetaTags;
+    use super::*;
+
+    #[test]
+    fn test_login_and_call() {
+        for encoding in [Encoding::Frame, Encoding::Stream, Encoding::Serial { with_crc: false }, Encoding::Serial { with_crc: true }] {
+            let ((mut client_reader, mut client_writer), (mut server_reader, mut server_writer)) = connect(encoding);
+            let users = HashMap::from([("test".to_string(), Password::from("secret"))]);
+            let client = async {
+                let params = LoginParams { user: "test".into(), password: Password::from("secret"), ..Default::default() };
+                let login_result = client::login(&mut *client_reader, &mut *client_writer, &params).await.unwrap();
+                assert_eq!(login_result.client_id(), Some(5));
+                client_writer.send_request("test", "ping", None).await.unwrap();
+                let resp = client_reader.receive_message().await.unwrap();
+                assert_eq!(resp.result().unwrap().as_str(), "pong");
+            };
+            let server = async {
+                let accepted = server::accept_login(&mut *server_reader, &mut *server_writer, &users, 5).await.unwrap();
+                assert_eq!(accepted.user, "test");
+                let rq = server_reader.receive_message().await.unwrap();
+                assert_eq!(rq.method(), Some("ping"));
+                let mut resp = rq.prepare_response().unwrap();
+                resp.set_result("pong");
+                server_writer.send_message(resp).await.unwrap();
+            };
+            block_on(futures::future::join(client, server));
+            drop(server_writer);
+            assert!(block_on(client_reader.receive_frame()).unwrap_err().is_eof());
+        }
+    }
+
+    #[test]
+    fn test_pipe() {
+        let (mut reader, mut writer) = pipe();
+        block_on(async {
+            writer.write_all(b"hello").await.unwrap();
+            writer.write_all(b" world").await.unwrap();
+            drop(writer);
+            let mut data = vec![];
+            reader.read_to_end(&mut data).await.unwrap();
+            assert_eq!(data, b"hello world");
+        });
+    }
+}
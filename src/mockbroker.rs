@@ -0,0 +1,381 @@
+//! Lightweight in-process broker stand-in for integration tests.
+//!
+//! The mock broker accepts `hello`/`login` of the configured users, assigns client IDs
+//! and answers requests from a table of scripted responses or by a handler closure.
+//! It also understands `.app:ping` and `.broker/app:subscribe`/`unsubscribe`, signals sent
+//! by [`MockBrokerHandle::send_signal`] or by the clients are delivered to the subscribers.
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::{Arc, Mutex};
+use async_std::task;
+use futures::channel::mpsc;
+use futures::io::BufWriter;
+use futures::{select, AsyncRead, AsyncReadExt, AsyncWrite, FutureExt, StreamExt};
+use log::*;
+use shvproto::RpcValue;
+use url::Url;
+use crate::client::Password;
+use crate::framerw::{FrameReader, FrameWriter};
+use crate::rpc::SubscriptionPattern;
+use crate::rpcmessage::{CliId, RpcError, RpcErrorCode};
+use crate::server;
+use crate::streamrw::{StreamFrameReader, StreamFrameWriter};
+use crate::{Error, RpcMessage, RpcMessageMetaTags};
+
+pub type RequestHandler = Box<dyn Fn(&RpcMessage) -> Option<Result<RpcValue, RpcError>> + Send + Sync>;
+
+#[derive(Default)]
+pub struct MockBroker {
+    users: HashMap<String, Password>,
+    responses: HashMap<(String, String), Result<RpcValue, RpcError>>,
+    handler: Option<RequestHandler>,
+}
+
+impl MockBroker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn with_user(mut self, user: &str, password: impl Into<Password>) -> Self {
+        self.users.insert(user.to_string(), password.into());
+        self
+    }
+    /// Requests of `method` on `shv_path` are answered by `result`.
+    pub fn with_response(mut self, shv_path: &str, method: &str, result: Result<RpcValue, RpcError>) -> Self {
+        self.responses.insert((shv_path.to_string(), method.to_string()), result);
+        self
+    }
+    /// Requests not found in the response table are passed to `handler`,
+    /// `MethodNotFound` error is sent back if it returns `None`.
+    pub fn with_handler(mut self, handler: impl Fn(&RpcMessage) -> Option<Result<RpcValue, RpcError>> + Send + Sync + 'static) -> Self {
+        self.handler = Some(Box::new(handler));
+        self
+    }
+    /// Starts listening on a random TCP port on the loopback interface.
+    pub async fn listen_tcp(self) -> crate::Result<MockBrokerHandle> {
+        let listener = async_std::net::TcpListener::bind("127.0.0.1:0").await?;
+        let url = Url::parse(&format!("tcp://{}", listener.local_addr()?)).map_err(|err| Error::InvalidUrl(err.to_string()))?;
+        let state = Arc::new(BrokerState::new(self));
+        let accept_state = state.clone();
+        let task = task::spawn(async move {
+            loop {
+                match listener.accept().await {
+                    Ok((stream, _)) => {
+                        let (reader, writer) = stream.split();
+                        task::spawn(serve_client(reader, writer, accept_state.clone()));
+                    }
+                    Err(err) => { warn!("Mock broker accept error: {err}") }
+                }
+            }
+        });
+        Ok(MockBrokerHandle { url, state, task })
+    }
+    /// Starts listening on a Unix socket at `path`, the path must not exist.
+    #[cfg(unix)]
+    pub async fn listen_unix(self, path: &str) -> crate::Result<MockBrokerHandle> {
+        let listener = async_std::os::unix::net::UnixListener::bind(path).await?;
+        let url = Url::parse(&format!("unix:{path}")).map_err(|err| Error::InvalidUrl(err.to_string()))?;
+        let state = Arc::new(BrokerState::new(self));
+        let accept_state = state.clone();
+        let task = task::spawn(async move {
+            loop {
+                match listener.accept().await {
+                    Ok((stream, _)) => {
+                        let (reader, writer) = stream.split();
+                        task::spawn(serve_client(reader, writer, accept_state.clone()));
+                    }
+                    Err(err) => { warn!("Mock broker accept error: {err}") }
+                }
+            }
+        });
+        Ok(MockBrokerHandle { url, state, task })
+    }
+}
+
+enum ClientCommand {
+    Send(RpcMessage),
+    Disconnect,
+}
+
+struct Client {
+    sender: mpsc::UnboundedSender<ClientCommand>,
+    subscriptions: Vec<SubscriptionPattern>,
+}
+
+struct BrokerState {
+    config: MockBroker,
+    next_client_id: AtomicI32,
+    clients: Mutex<HashMap<CliId, Client>>,
+}
+impl BrokerState {
+    fn new(config: MockBroker) -> Self {
+        Self {
+            config,
+            next_client_id: AtomicI32::new(1),
+            clients: Mutex::new(HashMap::new()),
+        }
+    }
+    fn send_signal(&self, signal: &RpcMessage) -> usize {
+        let shv_path = signal.shv_path().unwrap_or_default();
+        let method = signal.method().unwrap_or_default();
+        let clients = self.clients.lock().unwrap();
+        clients.values()
+            .filter(|client| client.subscriptions.iter().any(|subscription| subscription.match_shv_method(shv_path, method)))
+            .filter(|client| client.sender.unbounded_send(ClientCommand::Send(signal.clone())).is_ok())
+            .count()
+    }
+    fn process_request(&self, client_id: CliId, rq: &RpcMessage) -> Result<RpcValue, RpcError> {
+        let shv_path = rq.shv_path().unwrap_or_default();
+        let method = rq.method().unwrap_or_default();
+        match (shv_path, method) {
+            (".app", "ping") => {
+                return Ok(RpcValue::null())
+            }
+            (".broker/app", "subscribe") => {
+                let pattern = SubscriptionPattern::from_rpcvalue(rq.param().unwrap_or_default())
+                    .map_err(|err| RpcError::new(RpcErrorCode::InvalidParam, err.to_string()))?;
+                if let Some(client) = self.clients.lock().unwrap().get_mut(&client_id) {
+                    client.subscriptions.push(pattern);
+                }
+                return Ok(RpcValue::from(true))
+            }
+            (".broker/app", "unsubscribe") => {
+                let pattern = SubscriptionPattern::from_rpcvalue(rq.param().unwrap_or_default())
+                    .map_err(|err| RpcError::new(RpcErrorCode::InvalidParam, err.to_string()))?;
+                let mut clients = self.clients.lock().unwrap();
+                let Some(client) = clients.get_mut(&client_id) else {
+                    return Ok(RpcValue::from(false))
+                };
+                let count = client.subscriptions.len();
+                client.subscriptions.retain(|subscription| subscription != &pattern);
+                return Ok(RpcValue::from(client.subscriptions.len() < count))
+            }
+            _ => { }
+        }
+        if let Some(result) = self.config.responses.get(&(shv_path.to_string(), method.to_string())) {
+            return match result {
+                Ok(result) => { Ok(result.clone()) }
+                Err(err) => { Err(RpcError::new(err.code, err.message.clone())) }
+            }
+        }
+        if let Some(result) = self.config.handler.as_ref().and_then(|handler| handler(rq)) {
+            return result
+        }
+        Err(RpcError::new(RpcErrorCode::MethodNotFound, format!("Method: '{shv_path}:{method}' not found")))
+    }
+}
+
+async fn serve_client<R, W>(reader: R, writer: W, state: Arc<BrokerState>)
+where
+    R: AsyncRead + Unpin + Send,
+    W: AsyncWrite + Unpin + Send,
+{
+    let mut frame_reader = StreamFrameReader::new(reader);
+    let mut frame_writer = StreamFrameWriter::new(BufWriter::new(writer));
+    let pending = match server::verify_login(&mut frame_reader, &mut frame_writer, &state.config.users).await {
+        Ok(pending) => { pending }
+        Err(err) => {
+            warn!("Mock broker login error: {err}");
+            return
+        }
+    };
+    // Registered before the login response, the client can be addressed as soon as it is logged in
+    let client_id = state.next_client_id.fetch_add(1, Ordering::SeqCst);
+    let (sender, mut commands) = mpsc::unbounded();
+    state.clients.lock().unwrap().insert(client_id, Client { sender, subscriptions: vec![] });
+    match pending.accept(&mut frame_writer, client_id).await {
+        Ok(accepted) => {
+            debug!("Mock broker client {client_id} logged in as '{}'", accepted.user);
+        }
+        Err(err) => {
+            warn!("Mock broker login error: {err}");
+            state.clients.lock().unwrap().remove(&client_id);
+            return
+        }
+    }
+    loop {
+        select! {
+            frame = frame_reader.receive_frame().fuse() => {
+                let msg = match frame.and_then(|frame| frame.to_rpcmesage()) {
+                    Ok(msg) => { msg }
+                    Err(err) if err.is_eof() => { break }
+                    Err(err) => {
+                        warn!("Mock broker client {client_id} receive error: {err}");
+                        continue
+                    }
+                };
+                if msg.is_signal() {
+                    state.send_signal(&msg);
+                } else if msg.is_request() {
+                    let result = state.process_request(client_id, &msg);
+                    let Ok(mut resp) = msg.prepare_response() else { continue };
+                    resp.set_result_or_error(result);
+                    if let Err(err) = frame_writer.send_message(resp).await {
+                        warn!("Mock broker client {client_id} send error: {err}");
+                        break
+                    }
+                }
+            }
+            command = commands.next() => {
+                match command {
+                    Some(ClientCommand::Send(msg)) => {
+                        if let Err(err) = frame_writer.send_message(msg).await {
+                            warn!("Mock broker client {client_id} send error: {err}");
+                            break
+                        }
+                    }
+                    Some(ClientCommand::Disconnect) | None => { break }
+                }
+            }
+        }
+    }
+    state.clients.lock().unwrap().remove(&client_id);
+    debug!("Mock broker client {client_id} disconnected");
+}
+
+pub struct MockBrokerHandle {
+    url: Url,
+    state: Arc<BrokerState>,
+    task: task::JoinHandle<()>,
+}
+
+impl MockBrokerHandle {
+    /// URL to pass to [`client::connect`](crate::client::connect).
+    pub fn url(&self) -> &Url {
+        &self.url
+    }
+    /// IDs of the clients currently logged in.
+    pub fn clients(&self) -> Vec<CliId> {
+        let mut ids: Vec<_> = self.state.clients.lock().unwrap().keys().copied().collect();
+        ids.sort();
+        ids
+    }
+    /// Sends a signal to the subscribed clients, returns the number of clients it was sent to.
+    pub fn send_signal(&self, shv_path: &str, method: &str, param: Option<RpcValue>) -> usize {
+        self.state.send_signal(&RpcMessage::new_signal(shv_path, method, param))
+    }
+    /// Closes the connections of all the clients, the broker keeps listening.
+    pub fn disconnect_all(&self) {
+        for client in self.state.clients.lock().unwrap().values() {
+            let _ = client.sender.unbounded_send(ClientCommand::Disconnect);
+        }
+    }
+    /// Stops listening and closes all the client connections.
+    pub async fn shutdown(self) {
+        self.disconnect_all();
+        self.task.cancel().await;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+    use crate::client::{self, LoginParams};
+    use crate::framerw::{BoxedFrameReader, BoxedFrameWriter};
+    use crate::reconnect::{ConnectionEvent, ConnectionSupervisor, ReconnectPolicy};
+    use crate::Error;
+    use super::*;
+
+    async fn call(reader: &mut BoxedFrameReader, writer: &mut BoxedFrameWriter, shv_path: &str, method: &str, param: Option<RpcValue>) -> RpcMessage {
+        writer.send_request(shv_path, method, param).await.unwrap();
+        reader.receive_message().await.unwrap()
+    }
+
+    fn login_params() -> LoginParams {
+        LoginParams { user: "test".into(), password: Password::from("secret"), ..Default::default() }
+    }
+
+    #[async_std::test]
+    async fn test_login_and_requests() {
+        let broker = MockBroker::new()
+            .with_user("test", "secret")
+            .with_response("test/device", "get", Ok(RpcValue::from(42)))
+            .with_response("test/device", "set", Err(RpcError::new(RpcErrorCode::PermissionDenied, "read only")))
+            .with_handler(|rq| (rq.method() == Some("echo")).then(|| Ok(rq.param().cloned().unwrap_or_default())))
+            .listen_tcp().await.unwrap();
+        let (mut reader, mut writer) = client::connect(broker.url()).await.unwrap();
+        let login_result = client::login(&mut *reader, &mut *writer, &login_params()).await.unwrap();
+        assert_eq!(login_result.client_id(), Some(1));
+        assert_eq!(broker.clients(), vec![1]);
+
+        assert_eq!(call(&mut reader, &mut writer, "test/device", "get", None).await.result().unwrap(), &RpcValue::from(42));
+        assert_eq!(call(&mut reader, &mut writer, "test/device", "set", None).await.error().unwrap().code, RpcErrorCode::PermissionDenied);
+        assert_eq!(call(&mut reader, &mut writer, "foo", "echo", Some("hello".into())).await.result().unwrap().as_str(), "hello");
+        assert_eq!(call(&mut reader, &mut writer, "foo", "bar", None).await.error().unwrap().code, RpcErrorCode::MethodNotFound);
+
+        let (mut reader, mut writer) = client::connect(broker.url()).await.unwrap();
+        let params = LoginParams { password: Password::from("bad"), ..login_params() };
+        let err = client::login(&mut *reader, &mut *writer, &params).await.unwrap_err();
+        assert!(matches!(err, Error::LoginRejected(_)));
+        broker.shutdown().await;
+    }
+
+    #[async_std::test]
+    async fn test_subscription_and_disconnect() {
+        let broker = MockBroker::new().with_user("test", "secret").listen_tcp().await.unwrap();
+        let (mut reader, mut writer) = client::connect(broker.url()).await.unwrap();
+        client::login(&mut *reader, &mut *writer, &login_params()).await.unwrap();
+        let mut subscription = shvproto::Map::new();
+        subscription.insert("paths".into(), RpcValue::from("test/**"));
+        writer.send_request(".broker/app", "subscribe", Some(RpcValue::from(subscription))).await.unwrap();
+        assert!(reader.receive_message().await.unwrap().result().unwrap().as_bool());
+
+        assert_eq!(broker.send_signal("other/device", "chng", None), 0);
+        assert_eq!(broker.send_signal("test/device", "chng", Some(1.into())), 1);
+        let signal = reader.receive_message().await.unwrap();
+        assert_eq!(signal.shv_path(), Some("test/device"));
+        assert_eq!(signal.param(), Some(&RpcValue::from(1)));
+
+        broker.disconnect_all();
+        assert!(reader.receive_frame().await.unwrap_err().is_eof());
+        broker.shutdown().await;
+    }
+
+    #[async_std::test]
+    async fn test_supervisor_stops_when_client_dropped() {
+        let broker = MockBroker::new().with_user("test", "secret").listen_tcp().await.unwrap();
+        let url = broker.url().clone();
+        let (supervisor, mut events) = ConnectionSupervisor::new(
+            move || {
+                let url = url.clone();
+                async move { client::connect(&url).await }
+            },
+            login_params(),
+            ReconnectPolicy::new(Duration::from_millis(10)),
+        );
+        let supervisor = task::spawn(supervisor.run());
+        let Some(ConnectionEvent::Connected { client, .. }) = events.next().await else { panic!("Connected expected") };
+        drop(client);
+        assert!(events.next().await.is_none());
+        supervisor.await.unwrap();
+        broker.shutdown().await;
+    }
+
+    #[cfg(unix)]
+    #[async_std::test]
+    async fn test_reconnect() {
+        let path = std::env::temp_dir().join(format!("shvrpc-mockbroker-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let broker = MockBroker::new().with_user("test", "secret").listen_unix(path.to_str().unwrap()).await.unwrap();
+        let url = broker.url().clone();
+        let (supervisor, mut events) = ConnectionSupervisor::new(
+            move || {
+                let url = url.clone();
+                async move { client::connect(&url).await }
+            },
+            login_params(),
+            ReconnectPolicy::new(Duration::from_millis(10)),
+        );
+        let supervisor = task::spawn(supervisor.run());
+        // The connection is closed when the client is dropped, keep it
+        let Some(ConnectionEvent::Connected { login_result, client: _client, .. }) = events.next().await else { panic!("Connected expected") };
+        assert_eq!(login_result.client_id(), Some(1));
+        broker.disconnect_all();
+        assert!(matches!(events.next().await, Some(ConnectionEvent::Disconnected)));
+        let Some(ConnectionEvent::Connected { login_result, .. }) = events.next().await else { panic!("Connected expected") };
+        assert_eq!(login_result.client_id(), Some(2));
+        drop(events);
+        broker.shutdown().await;
+        supervisor.cancel().await;
+        let _ = std::fs::remove_file(&path);
+    }
+}
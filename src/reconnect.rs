@@ -0,0 +1,149 @@
+use std::future::Future;
+use std::time::Duration;
+use futures::channel::mpsc;
+use futures_timer::Delay;
+use log::*;
+use crate::client::{ClientConfig, login, LoginParams, LoginResult};
+use crate::framerw::{BoxedFrameReader, BoxedFrameWriter};
+use crate::rpcclient::{Heartbeat, RpcClient, SignalReceiver};
+
+#[derive(Clone, Debug)]
+pub struct ReconnectPolicy {
+    pub interval: Duration,
+    pub max_interval: Duration,
+    pub exponential_backoff: bool,
+}
+impl ReconnectPolicy {
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            max_interval: interval,
+            exponential_backoff: false,
+        }
+    }
+    /// Double the wait time after each failed attempt, up to `max_interval`.
+    pub fn with_exponential_backoff(mut self, max_interval: Duration) -> Self {
+        self.exponential_backoff = true;
+        self.max_interval = max_interval;
+        self
+    }
+    /// Returns `None` if the config does not request reconnecting.
+    pub fn from_config(config: &ClientConfig) -> crate::Result<Option<Self>> {
+        Ok(config.reconnect_interval_duration()?.map(Self::new))
+    }
+    fn next_interval(&self, interval: Duration) -> Duration {
+        if self.exponential_backoff {
+            std::cmp::min(interval * 2, self.max_interval)
+        } else {
+            self.interval
+        }
+    }
+}
+
+pub enum ConnectionEvent {
+    Connected {
+        login_result: LoginResult,
+        client: RpcClient,
+        signals: SignalReceiver,
+    },
+    Disconnected,
+}
+
+pub type Connection = (BoxedFrameReader, BoxedFrameWriter);
+
+// How an established session ended
+enum SessionEnd {
+    // The application dropped the client or the event receiver, there is no one to reconnect for
+    Closed,
+    ConnectionLost,
+}
+
+/// Keeps a broker connection alive.
+///
+/// `connect` is called to open the transport each time the connection is lost,
+/// the login is redone with the original [`LoginParams`] and a new [`RpcClient`]
+/// is reported by [`ConnectionEvent::Connected`] together with the client ID
+/// assigned by the broker. The supervisor finishes once the event receiver
+/// is dropped or the application drops all the handles of the current [`RpcClient`].
+pub struct ConnectionSupervisor<C> {
+    connect: C,
+    login_params: LoginParams,
+    policy: ReconnectPolicy,
+    event_sender: mpsc::UnboundedSender<ConnectionEvent>,
+}
+impl<C, F> ConnectionSupervisor<C>
+where
+    C: FnMut() -> F,
+    F: Future<Output = crate::Result<Connection>>,
+{
+    pub fn new(connect: C, login_params: LoginParams, policy: ReconnectPolicy) -> (Self, mpsc::UnboundedReceiver<ConnectionEvent>) {
+        let (event_sender, event_receiver) = mpsc::unbounded();
+        let supervisor = Self {
+            connect,
+            login_params,
+            policy,
+            event_sender,
+        };
+        (supervisor, event_receiver)
+    }
+    pub async fn run(mut self) -> crate::Result<()> {
+        let mut interval = self.policy.interval;
+        loop {
+            match self.run_session().await {
+                Ok(SessionEnd::Closed) => {
+                    info!("Client closed, connection supervisor finished");
+                    return Ok(());
+                }
+                Ok(SessionEnd::ConnectionLost) => {
+                    interval = self.policy.interval;
+                }
+                Err(err) => {
+                    warn!("Connection error: {err}");
+                }
+            }
+            if self.event_sender.is_closed() {
+                return Ok(());
+            }
+            info!("Reconnecting in {} ms", interval.as_millis());
+            Delay::new(interval).await;
+            interval = self.policy.next_interval(interval);
+        }
+    }
+    // Returns how the session ended if it was established.
+    async fn run_session(&mut self) -> crate::Result<SessionEnd> {
+        let (mut frame_reader, mut frame_writer) = (self.connect)().await?;
+        let login_result = login(frame_reader.as_mut(), frame_writer.as_mut(), &self.login_params).await?;
+        info!("Connected to broker, client id: {:?}", login_result.client_id());
+        let (client, signals, task) = RpcClient::new(frame_reader, frame_writer);
+        let task = task.with_heartbeat(Heartbeat::from_login_params(&self.login_params));
+        if self.event_sender.unbounded_send(ConnectionEvent::Connected { login_result, client, signals }).is_err() {
+            return Ok(SessionEnd::Closed);
+        }
+        // The task finishes without error only when all the client handles are dropped
+        if let Err(err) = task.run().await {
+            warn!("Connection lost: {err}");
+            let _ = self.event_sender.unbounded_send(ConnectionEvent::Disconnected);
+            return Ok(SessionEnd::ConnectionLost);
+        }
+        Ok(SessionEnd::Closed)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_next_interval() {
+        let policy = ReconnectPolicy::new(Duration::from_secs(1));
+        assert_eq!(policy.next_interval(Duration::from_secs(1)), Duration::from_secs(1));
+        let policy = policy.with_exponential_backoff(Duration::from_secs(5));
+        let mut interval = policy.interval;
+        let mut intervals = vec![];
+        for _ in 0 .. 4 {
+            interval = policy.next_interval(interval);
+            intervals.push(interval.as_secs());
+        }
+        assert_eq!(intervals, vec![2, 4, 5, 5]);
+    }
+}
@@ -0,0 +1,115 @@
+//! Redaction of sensitive values in logged messages.
+//!
+//! Frames and messages are formatted with the values of sensitive map keys, like the
+//! login `password`, replaced by [`REDACTED`]. Extra keys can be added by [`add_redacted_keys`].
+use std::sync::RwLock;
+use shvproto::{RpcValue, Value};
+
+pub const REDACTED: &str = "***";
+const DEFAULT_REDACTED_KEYS: [&str; 1] = ["password"];
+
+static EXTRA_REDACTED_KEYS: RwLock<Vec<String>> = RwLock::new(Vec::new());
+
+/// Values of `keys` are redacted in addition to the default ones, in any map at any depth.
+pub fn add_redacted_keys<S: Into<String>>(keys: impl IntoIterator<Item = S>) {
+    let mut extra_keys = EXTRA_REDACTED_KEYS.write().unwrap_or_else(|err| err.into_inner());
+    extra_keys.extend(keys.into_iter().map(Into::into));
+}
+
+pub fn is_redacted_key(key: &str) -> bool {
+    DEFAULT_REDACTED_KEYS.contains(&key)
+        || EXTRA_REDACTED_KEYS.read().unwrap_or_else(|err| err.into_inner()).iter().any(|k| k == key)
+}
+
+/// Returns a copy of `rv` with the values of the sensitive map keys replaced.
+pub fn redact(rv: &RpcValue) -> RpcValue {
+    redact_keys(rv, &is_redacted_key)
+}
+
+fn redact_keys(rv: &RpcValue, is_redacted: &dyn Fn(&str) -> bool) -> RpcValue {
+    let redacted = match rv.value() {
+        Value::List(list) => {
+            RpcValue::from(list.iter().map(|val| redact_keys(val, is_redacted)).collect::<shvproto::List>())
+        }
+        Value::Map(map) => {
+            RpcValue::from(map.iter().map(|(key, val)| {
+                let val = if is_redacted(key) { RpcValue::from(REDACTED) } else { redact_keys(val, is_redacted) };
+                (key.clone(), val)
+            }).collect::<shvproto::Map>())
+        }
+        Value::IMap(map) => {
+            RpcValue::from(map.iter().map(|(key, val)| (*key, redact_keys(val, is_redacted))).collect::<shvproto::IMap>())
+        }
+        _ => { return rv.clone() }
+    };
+    if rv.meta().is_empty() {
+        redacted
+    } else {
+        redacted.set_meta(Some(rv.meta().clone()))
+    }
+}
+
+pub fn redact_json(value: &serde_json::Value) -> serde_json::Value {
+    redact_json_keys(value, &is_redacted_key)
+}
+
+fn redact_json_keys(value: &serde_json::Value, is_redacted: &dyn Fn(&str) -> bool) -> serde_json::Value {
+    match value {
+        serde_json::Value::Array(array) => {
+            array.iter().map(|val| redact_json_keys(val, is_redacted)).collect::<Vec<_>>().into()
+        }
+        serde_json::Value::Object(object) => {
+            object.iter().map(|(key, val)| {
+                let val = if is_redacted(key) { REDACTED.into() } else { redact_json_keys(val, is_redacted) };
+                (key.clone(), val)
+            }).collect::<serde_json::Map<_, _>>().into()
+        }
+        _ => { value.clone() }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::client::{LoginParams, Password};
+    use crate::rpcframe::Protocol;
+    use crate::RpcMessage;
+    use shvproto::Map;
+    use super::*;
+
+    #[test]
+    fn test_redact_login() {
+        let login_params = LoginParams { user: "test".into(), password: Password::from("secret"), ..Default::default() };
+        let params = login_params.to_rpcvalue("1234").unwrap();
+        let password = login_params.login_password("1234").unwrap();
+        let rq = RpcMessage::new_request("", "login", Some(params));
+        for protocol in [Protocol::ChainPack, Protocol::Cpon, Protocol::Json] {
+            let frame = rq.to_frame_with_protocol(protocol).unwrap();
+            for text in [frame.to_string(), format!("{frame:?}")] {
+                assert!(!text.contains(&password), "{text}");
+                assert!(text.contains(REDACTED) && text.contains("\"test\""), "{text}");
+            }
+        }
+        for text in [rq.to_string(), format!("{rq:?}")] {
+            assert!(!text.contains(&password) && text.contains(REDACTED), "{text}");
+            assert!(text.contains("login"), "{text}");
+        }
+        let text = format!("{login_params:?}");
+        assert!(!text.contains("secret") && text.contains(REDACTED), "{text}");
+    }
+
+    #[test]
+    fn test_redact_extra_keys() {
+        let is_redacted = |key: &str| key == "token" || is_redacted_key(key);
+        let mut params = Map::new();
+        params.insert("user".into(), RpcValue::from("test"));
+        params.insert("password".into(), RpcValue::from("secret"));
+        params.insert("token".into(), RpcValue::from("abcd"));
+        let rv = RpcValue::from(vec![RpcValue::from(params)]);
+        let text = redact_keys(&rv, &is_redacted).to_cpon();
+        assert!(!text.contains("secret") && !text.contains("abcd") && text.contains("test"), "{text}");
+        assert!(redact(&rv).to_cpon().contains("abcd"));
+        let value = serde_json::json!([{ "user": "test", "password": "secret", "token": "abcd" }]);
+        let text = redact_json_keys(&value, &is_redacted).to_string();
+        assert!(!text.contains("secret") && !text.contains("abcd") && text.contains("test"), "{text}");
+    }
+}
@@ -2,6 +2,7 @@ use std::fmt::{Display, Formatter};
 use glob::Pattern;
 use shvproto::RpcValue;
 use shvproto::Map;
+use crate::Error;
 
 #[derive(Debug, Clone)]
 pub struct Subscription {
@@ -69,10 +70,10 @@ impl SubscriptionPattern {
                             paths,
                         })
                     }
-                    Err(err) => { Err(format!("{}", &err).into()) }
+                    Err(err) => { Err(Error::InvalidPattern(err)) }
                 }
             }
-            Err(err) => { Err(format!("{}", &err).into()) }
+            Err(err) => { Err(Error::InvalidPattern(err)) }
         }
     }
     pub fn match_shv_method(&self, path: &str, method: &str) -> bool {
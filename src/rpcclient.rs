@@ -0,0 +1,299 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use futures::channel::{mpsc, oneshot};
+use futures::future::Fuse;
+use futures::{FutureExt, pin_mut, select, StreamExt};
+use futures_timer::Delay;
+use log::*;
+use shvproto::RpcValue;
+use crate::client::LoginParams;
+use crate::framerw::{BoxedFrameReader, BoxedFrameWriter, FrameReader, FrameWriter};
+use crate::rpcmessage::{RpcError, RpcErrorCode, RqId};
+use crate::{Error, RpcMessage, RpcMessageMetaTags};
+
+pub type SignalReceiver = mpsc::UnboundedReceiver<RpcMessage>;
+type PendingRequests = Arc<Mutex<HashMap<RqId, oneshot::Sender<RpcMessage>>>>;
+
+enum ClientCommand {
+    SendRequest {
+        request: RpcMessage,
+        response_sender: oneshot::Sender<RpcMessage>,
+    },
+    SendMessage {
+        message: RpcMessage,
+    },
+}
+
+/// Keepalive sent by [`RpcClientTask`] when nothing was written for `interval`.
+#[derive(Clone, Debug)]
+pub struct Heartbeat {
+    pub interval: Duration,
+    pub shv_path: String,
+    pub method: String,
+    /// Number of unanswered pings after which the connection is considered dead.
+    pub max_missed: u32,
+}
+impl Heartbeat {
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            shv_path: ".app".into(),
+            method: "ping".into(),
+            max_missed: 3,
+        }
+    }
+    pub fn from_login_params(login_params: &LoginParams) -> Self {
+        Self::new(login_params.heartbeat_interval)
+    }
+    pub fn with_method(mut self, shv_path: &str, method: &str) -> Self {
+        self.shv_path = shv_path.into();
+        self.method = method.into();
+        self
+    }
+    pub fn with_max_missed(mut self, max_missed: u32) -> Self {
+        self.max_missed = max_missed;
+        self
+    }
+}
+
+fn connection_closed_error() -> RpcError {
+    RpcError::new(RpcErrorCode::MethodCallCancelled, "Connection closed")
+}
+
+/// Handle used to issue RPC calls over a connection owned by [`RpcClientTask`].
+///
+/// Responses are matched to requests by request ID, signals are delivered to
+/// the [`SignalReceiver`] returned by [`RpcClient::new`].
+#[derive(Clone)]
+pub struct RpcClient {
+    command_sender: mpsc::UnboundedSender<ClientCommand>,
+}
+impl RpcClient {
+    pub fn new(frame_reader: BoxedFrameReader, frame_writer: BoxedFrameWriter) -> (Self, SignalReceiver, RpcClientTask) {
+        let (command_sender, command_receiver) = mpsc::unbounded();
+        let (signal_sender, signal_receiver) = mpsc::unbounded();
+        let task = RpcClientTask {
+            frame_reader,
+            frame_writer,
+            command_receiver,
+            signal_sender,
+            heartbeat: None,
+        };
+        (Self { command_sender }, signal_receiver, task)
+    }
+    pub async fn call(&self, shv_path: &str, method: &str, param: Option<RpcValue>) -> Result<RpcValue, RpcError> {
+        let request = RpcMessage::new_request(shv_path, method, param);
+        let response = self.send_request(request).await?;
+        response.result().cloned()
+    }
+    pub async fn send_request(&self, request: RpcMessage) -> Result<RpcMessage, RpcError> {
+        let (response_sender, response_receiver) = oneshot::channel();
+        self.command_sender
+            .unbounded_send(ClientCommand::SendRequest { request, response_sender })
+            .map_err(|_| connection_closed_error())?;
+        response_receiver.await.map_err(|_| connection_closed_error())
+    }
+    pub fn send_message(&self, message: RpcMessage) -> crate::Result<()> {
+        self.command_sender
+            .unbounded_send(ClientCommand::SendMessage { message })
+            .map_err(|_| std::io::Error::from(std::io::ErrorKind::NotConnected).into())
+    }
+    pub fn is_connected(&self) -> bool {
+        !self.command_sender.is_closed()
+    }
+}
+
+/// Drives the connection of an [`RpcClient`].
+///
+/// The future returned by [`RpcClientTask::run`] must be spawned on the executor
+/// of the application. It resolves when the connection is closed by the peer,
+/// on I/O error, when the [`Heartbeat`] pings are not answered, or when all
+/// the [`RpcClient`] handles are dropped. Pending calls are cancelled at that point.
+pub struct RpcClientTask {
+    frame_reader: BoxedFrameReader,
+    frame_writer: BoxedFrameWriter,
+    command_receiver: mpsc::UnboundedReceiver<ClientCommand>,
+    signal_sender: mpsc::UnboundedSender<RpcMessage>,
+    heartbeat: Option<Heartbeat>,
+}
+impl RpcClientTask {
+    pub fn with_heartbeat(mut self, heartbeat: Heartbeat) -> Self {
+        self.heartbeat = Some(heartbeat);
+        self
+    }
+    pub async fn run(self) -> crate::Result<()> {
+        let RpcClientTask { mut frame_reader, mut frame_writer, command_receiver, signal_sender, heartbeat } = self;
+        let pending = PendingRequests::default();
+        let (reply_sender, reply_receiver) = mpsc::unbounded();
+        let read_loop = read_loop(frame_reader.as_mut(), pending.clone(), signal_sender, reply_sender).fuse();
+        let write_loop = write_loop(frame_writer.as_mut(), pending, command_receiver, reply_receiver, heartbeat).fuse();
+        pin_mut!(read_loop, write_loop);
+        select! {
+            res = read_loop => res,
+            res = write_loop => res,
+        }
+    }
+}
+
+async fn read_loop(
+    frame_reader: &mut (dyn FrameReader + Send),
+    pending: PendingRequests,
+    signal_sender: mpsc::UnboundedSender<RpcMessage>,
+    reply_sender: mpsc::UnboundedSender<RpcMessage>,
+) -> crate::Result<()> {
+    loop {
+        let frame = frame_reader.receive_frame().await?;
+        if frame.is_reset_session() {
+            debug!("Reset session received, ignored");
+            continue
+        }
+        let msg = match frame.to_rpcmesage() {
+            Ok(msg) => { msg }
+            Err(err) => {
+                warn!("Invalid message received: {err}");
+                continue
+            }
+        };
+        if msg.is_response() {
+            let Some(rqid) = msg.request_id() else { continue };
+            let response_sender = pending.lock().expect("Pending requests lock should not be poisoned").remove(&rqid);
+            match response_sender {
+                None => { debug!("Dropping response to unknown request id: {rqid}") }
+                Some(response_sender) => { let _ = response_sender.send(msg); }
+            }
+        } else if msg.is_signal() {
+            // Signals are dropped silently if the application does not read them.
+            let _ = signal_sender.unbounded_send(msg);
+        } else if msg.is_request() {
+            let mut resp = match msg.prepare_response() {
+                Ok(resp) => { resp }
+                Err(err) => {
+                    warn!("Cannot prepare response to request: {msg} - {err}");
+                    continue
+                }
+            };
+            let errmsg = format!("Method: {}:{} not found", msg.shv_path().unwrap_or_default(), msg.method().unwrap_or_default());
+            resp.set_error(RpcError::new(RpcErrorCode::MethodNotFound, errmsg));
+            // The write loop is gone, the connection is being closed
+            reply_sender.unbounded_send(resp).map_err(|_| std::io::Error::from(std::io::ErrorKind::BrokenPipe))?;
+        }
+    }
+}
+
+async fn write_loop(
+    frame_writer: &mut (dyn FrameWriter + Send),
+    pending: PendingRequests,
+    mut command_receiver: mpsc::UnboundedReceiver<ClientCommand>,
+    mut reply_receiver: mpsc::UnboundedReceiver<RpcMessage>,
+    heartbeat: Option<Heartbeat>,
+) -> crate::Result<()> {
+    let mut last_write = Instant::now();
+    let mut ping_response: Option<(RqId, oneshot::Receiver<RpcMessage>)> = None;
+    let mut missed_pings = 0;
+    loop {
+        let mut heartbeat_timeout = match &heartbeat {
+            None => { Fuse::terminated() }
+            Some(heartbeat) => { Delay::new(heartbeat.interval.saturating_sub(last_write.elapsed())).fuse() }
+        };
+        select! {
+            command = command_receiver.next() => {
+                match command {
+                    None => { return Ok(()) }
+                    Some(ClientCommand::SendRequest { request, response_sender }) => {
+                        let Some(rqid) = request.request_id() else {
+                            warn!("Cannot send request without request id: {request}");
+                            continue
+                        };
+                        pending.lock().expect("Pending requests lock should not be poisoned").insert(rqid, response_sender);
+                        frame_writer.send_message(request).await?;
+                    }
+                    Some(ClientCommand::SendMessage { message }) => {
+                        frame_writer.send_message(message).await?;
+                    }
+                }
+            }
+            reply = reply_receiver.next() => {
+                if let Some(reply) = reply {
+                    frame_writer.send_message(reply).await?;
+                }
+            }
+            _ = heartbeat_timeout => {
+                let Some(heartbeat) = &heartbeat else { continue };
+                if last_write.elapsed() < heartbeat.interval {
+                    continue
+                }
+                if let Some((rqid, mut response)) = ping_response.take() {
+                    match response.try_recv() {
+                        Ok(Some(_)) => { missed_pings = 0 }
+                        _ => {
+                            missed_pings += 1;
+                            pending.lock().expect("Pending requests lock should not be poisoned").remove(&rqid);
+                        }
+                    }
+                }
+                if missed_pings >= heartbeat.max_missed {
+                    return Err(Error::HeartbeatTimeout { missed: missed_pings });
+                }
+                let request = RpcMessage::new_request(&heartbeat.shv_path, &heartbeat.method, None);
+                let rqid = request.request_id().expect("Request ID should exist here.");
+                let (response_sender, response_receiver) = oneshot::channel();
+                pending.lock().expect("Pending requests lock should not be poisoned").insert(rqid, response_sender);
+                ping_response = Some((rqid, response_receiver));
+                frame_writer.send_message(request).await?;
+            }
+        }
+        last_write = Instant::now();
+    }
+}
+
+#[cfg(all(test, feature = "async-std"))]
+mod test {
+    use crate::loopback::{self, Encoding};
+    use super::*;
+
+    #[async_std::test]
+    async fn test_call_and_signal() {
+        let ((client_reader, client_writer), (mut peer_reader, mut peer_writer)) = loopback::connect(Encoding::Frame);
+        let (client, mut signals, task) = RpcClient::new(client_reader, client_writer);
+        let task = async_std::task::spawn(task.run());
+        let peer = async_std::task::spawn(async move {
+            while let Ok(rq) = peer_reader.receive_message().await {
+                let signal = RpcMessage::new_signal("foo", "chng", Some(RpcValue::from(rq.method().unwrap())));
+                peer_writer.send_message(signal).await.unwrap();
+                let mut resp = rq.prepare_response().unwrap();
+                resp.set_result(rq.param().cloned().unwrap_or_default());
+                peer_writer.send_message(resp).await.unwrap();
+            }
+        });
+        let (res1, res2) = futures::join!(
+            client.call("foo", "bar", Some(RpcValue::from(1))),
+            client.call("foo", "baz", Some(RpcValue::from(2))),
+        );
+        assert_eq!(res1.unwrap(), RpcValue::from(1));
+        assert_eq!(res2.unwrap(), RpcValue::from(2));
+        let signal = signals.next().await.unwrap();
+        assert_eq!(signal.shv_path(), Some("foo"));
+        drop(client);
+        task.await.unwrap();
+        peer.await;
+    }
+
+    #[async_std::test]
+    async fn test_heartbeat_not_answered() {
+        let ((client_reader, client_writer), (mut peer_reader, _peer_writer)) = loopback::connect(Encoding::Frame);
+        let (_client, _signals, task) = RpcClient::new(client_reader, client_writer);
+        let heartbeat = Heartbeat::new(Duration::from_millis(10)).with_max_missed(2);
+        let res = task.with_heartbeat(heartbeat).run().await;
+        assert!(matches!(res, Err(Error::HeartbeatTimeout { missed: 2 })), "{res:?}");
+        let mut frames = vec![];
+        while let Ok(frame) = peer_reader.receive_frame().await {
+            frames.push(frame);
+        }
+        assert_eq!(frames.len(), 2);
+        for frame in frames {
+            assert_eq!(frame.shv_path(), Some(".app"));
+            assert_eq!(frame.method(), Some("ping"));
+        }
+    }
+}
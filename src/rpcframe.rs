@@ -1,11 +1,12 @@
 use std::fmt;
 use std::io::{BufReader};
-use shvproto::{ChainPackReader, ChainPackWriter, MetaMap, RpcValue};
+use shvproto::{ChainPackReader, ChainPackWriter, CponReader, CponWriter, MetaMap, RpcValue};
 use shvproto::writer::Writer;
 use shvproto::reader::Reader;
-use crate::{RpcMessage, rpcmessage, RpcMessageMetaTags, rpctype};
+use crate::{Error, json, RpcMessage, rpcmessage, RpcMessageMetaTags, rpctype};
+use crate::redact::{redact, redact_json};
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, PartialEq)]
 pub struct RpcFrame {
     pub protocol: Protocol,
     pub meta: MetaMap,
@@ -14,14 +15,43 @@ pub struct RpcFrame {
 
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub enum Protocol {
-    ResetSession = 0,
-    ChainPack = 1,
+    ResetSession,
+    ChainPack,
+    Cpon,
+    Json,
+    /// Frames of unknown protocols are kept opaque, so they can be passed through.
+    Unknown(u8),
+}
+impl From<u8> for Protocol {
+    fn from(value: u8) -> Self {
+        match value {
+            0 => Protocol::ResetSession,
+            1 => Protocol::ChainPack,
+            2 => Protocol::Cpon,
+            3 => Protocol::Json,
+            value => Protocol::Unknown(value),
+        }
+    }
+}
+impl From<Protocol> for u8 {
+    fn from(value: Protocol) -> Self {
+        match value {
+            Protocol::ResetSession => 0,
+            Protocol::ChainPack => 1,
+            Protocol::Cpon => 2,
+            Protocol::Json => 3,
+            Protocol::Unknown(value) => value,
+        }
+    }
 }
 impl fmt::Display for Protocol {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
         match self {
             Protocol::ChainPack => write!(fmt, "ChainPack"),
+            Protocol::Cpon => write!(fmt, "Cpon"),
+            Protocol::Json => write!(fmt, "Json"),
             Protocol::ResetSession => write!(fmt, "ResetSession"),
+            Protocol::Unknown(value) => write!(fmt, "Unknown({value})"),
         }
     }
 }
@@ -30,13 +60,32 @@ impl RpcFrame {
         RpcFrame { protocol, meta, data }
     }
     pub fn from_rpcmessage(msg: &RpcMessage) -> crate::Result<RpcFrame> {
+        Self::from_rpcmessage_with_protocol(msg, Protocol::ChainPack)
+    }
+    pub fn from_rpcmessage_with_protocol(msg: &RpcMessage, protocol: Protocol) -> crate::Result<RpcFrame> {
         let mut data = Vec::new();
-        {
-            let mut wr = ChainPackWriter::new(&mut data);
-            wr.write_value(msg.as_rpcvalue().value())?;
+        match protocol {
+            Protocol::ChainPack => {
+                let mut wr = ChainPackWriter::new(&mut data);
+                wr.write_value(msg.as_rpcvalue().value())?;
+            }
+            Protocol::Cpon => {
+                let mut wr = CponWriter::new(&mut data);
+                wr.write_value(msg.as_rpcvalue().value())?;
+            }
+            Protocol::Json => {
+                data = serde_json::to_vec(&json::body_to_json(msg.as_rpcvalue()))
+                    .map_err(|err| Error::InvalidMessage(err.to_string()))?;
+            }
+            Protocol::ResetSession | Protocol::Unknown(_) => {
+                return Err(Error::UnsupportedProtocol(protocol.into()));
+            }
         }
         let meta = msg.as_rpcvalue().meta().clone();
-        Ok(RpcFrame { protocol: Protocol::ChainPack, meta, data })
+        Ok(RpcFrame { protocol, meta, data })
+    }
+    pub fn is_reset_session(&self) -> bool {
+        self.protocol == Protocol::ResetSession
     }
     pub fn to_rpcmesage(&self) -> crate::Result<RpcMessage> {
         let mut buff = BufReader::new(&*self.data);
@@ -45,13 +94,24 @@ impl RpcFrame {
                 let mut rd = ChainPackReader::new(&mut buff);
                 rd.read_value()?
             }
-            _ => {
-                return Err("Invalid protocol".into());
+            Protocol::Cpon => {
+                let mut rd = CponReader::new(&mut buff);
+                rd.read_value()?
+            }
+            Protocol::Json => {
+                let body = json::body_from_json(&json::parse_object(&self.data)?)?;
+                return RpcMessage::from_rpcvalue(body.set_meta(Some(self.meta.clone())));
+            }
+            Protocol::ResetSession => {
+                return Err(Error::ResetSession);
+            }
+            Protocol::Unknown(protocol) => {
+                return Err(Error::UnsupportedProtocol(*protocol));
             }
         };
-        Ok(RpcMessage::from_rpcvalue(RpcValue::new(value, Some(self.meta.clone())))?)
+        RpcMessage::from_rpcvalue(RpcValue::new(value, Some(self.meta.clone())))
     }
-    pub fn prepare_response_meta(src: &MetaMap) -> Result<MetaMap, &'static str> {
+    pub fn prepare_response_meta(src: &MetaMap) -> crate::Result<MetaMap> {
         if src.is_request() {
             if let Some(rqid) = src.request_id() {
                 let mut dest = MetaMap::new();
@@ -60,9 +120,9 @@ impl RpcFrame {
                 dest.set_caller_ids(&src.caller_ids());
                 return Ok(dest)
             }
-            return Err("Request ID is missing")
+            return Err(Error::InvalidMessage("Request ID is missing".into()))
         }
-        Err("Not RPC Request")
+        Err(Error::InvalidMessage("Not RPC Request".into()))
     }
 }
 impl fmt::Display for RpcFrame {
@@ -73,10 +133,26 @@ impl fmt::Display for RpcFrame {
             write!(fmt, "{}", self.meta)?;
             if self.data.len() > 256 {
                 write!(fmt, "[ ... {} bytes of data ... ]", self.data.len())
+            } else if let Protocol::Unknown(protocol) = self.protocol {
+                write!(fmt, "[ {} bytes of protocol {} data ]", self.data.len(), protocol)
+            } else if self.protocol == Protocol::Json {
+                match serde_json::from_slice(&self.data) {
+                    Ok(value) => {
+                        write!(fmt, "{}", redact_json(&value))
+                    }
+                    Err(e) => {
+                        write!(fmt, "[ unpack error: {} ]", e)
+                    }
+                }
             } else {
-                match RpcValue::from_chainpack(&self.data) {
+                let rv = if self.protocol == Protocol::Cpon {
+                    RpcValue::from_cpon(&String::from_utf8_lossy(&self.data))
+                } else {
+                    RpcValue::from_chainpack(&self.data)
+                };
+                match rv {
                     Ok(rv) => {
-                        write!(fmt, "{}", rv.to_cpon())
+                        write!(fmt, "{}", redact(&rv).to_cpon())
                     }
                     Err(e) => {
                         write!(fmt, "[ unpack error: {} ]", e)
@@ -87,6 +163,13 @@ impl fmt::Display for RpcFrame {
     }
 }
 
+// Sensitive values are redacted like by Display
+impl fmt::Debug for RpcFrame {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "{} {}", self.protocol, self)
+    }
+}
+
 impl rpcmessage::RpcMessageMetaTags for RpcFrame {
     type Target = RpcFrame;
 
@@ -98,3 +181,19 @@ impl rpcmessage::RpcMessageMetaTags for RpcFrame {
         self
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    proptest::proptest! {
+        #[test]
+        fn test_to_rpcmessage_random_data(data: Vec<u8>) {
+            let mut meta = MetaMap::new();
+            meta.insert(rpctype::Tag::MetaTypeId as i32, RpcValue::from(rpctype::GlobalNS::MetaTypeID::ChainPackRpcMessage as i32));
+            let frame = RpcFrame::new(Protocol::ChainPack, meta, data);
+            let _ = frame.to_rpcmesage();
+            let _ = frame.to_string();
+        }
+    }
+}
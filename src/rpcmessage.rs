@@ -9,8 +9,10 @@ use std::fmt;
 use std::fmt::{Debug, Display, Formatter};
 use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
 use serde::de::Visitor;
-use crate::rpcframe::RpcFrame;
+use crate::Error;
+use crate::rpcframe::{Protocol, RpcFrame};
 use crate::rpctype;
+use crate::redact::redact;
 
 static G_RPC_REQUEST_COUNT: AtomicI64 = AtomicI64::new(0);
 
@@ -34,20 +36,20 @@ pub enum Tag {
 #[allow(dead_code)]
 pub enum Key {Params = 1, Result, Error, ErrorCode, ErrorMessage, MAX }
 
-#[derive(Clone, Debug)]
+#[derive(Clone)]
 pub struct RpcMessage (RpcValue);
 impl RpcMessage {
     pub fn from_meta(meta: MetaMap) -> Self {
         RpcMessage(RpcValue::from(IMap::new()).set_meta(Some(meta)))
     }
-    pub fn from_rpcvalue(rv: RpcValue) -> Result<Self, &'static str> {
+    pub fn from_rpcvalue(rv: RpcValue) -> crate::Result<Self> {
         if rv.meta().is_empty() {
-            return Err("Meta is empty.");
+            return Err(Error::InvalidMessage("Meta is empty.".into()));
         }
         if rv.is_imap() {
             return Ok(Self(rv))
         }
-        Err("Value must be IMap!")
+        Err(Error::InvalidMessage("Value must be IMap!".into()))
     }
     pub fn as_rpcvalue(&self) -> &RpcValue {
         &self.0
@@ -65,6 +67,9 @@ impl RpcMessage {
     pub fn to_frame(&self) -> crate::Result<RpcFrame> {
         RpcFrame::from_rpcmessage(self)
     }
+    pub fn to_frame_with_protocol(&self, protocol: Protocol) -> crate::Result<RpcFrame> {
+        RpcFrame::from_rpcmessage_with_protocol(self, protocol)
+    }
     pub fn param(&self) -> Option<&RpcValue> { self.key(Key::Params as i32) }
     pub fn set_param(&mut self, rv: impl Into<RpcValue>) -> &mut Self  { self.set_param_opt(Some(rv.into())) }
     pub fn set_param_opt(&mut self, rv: Option<RpcValue>) -> &mut Self  { self.set_key(Key::Params, rv); self }
@@ -158,10 +163,10 @@ impl RpcMessage {
         }
         msg
     }
-    pub fn prepare_response(&self) -> Result<Self, &'static str> {
+    pub fn prepare_response(&self) -> crate::Result<Self> {
         Self::prepare_response_from_meta(self.as_rpcvalue().meta())
     }
-    pub fn prepare_response_from_meta(meta: &MetaMap) -> Result<Self, &'static str> {
+    pub fn prepare_response_from_meta(meta: &MetaMap) -> crate::Result<Self> {
         let meta = RpcFrame::prepare_response_meta(meta)?;
         Ok(Self::from_meta(meta))
     }
@@ -202,7 +207,7 @@ pub trait RpcMessageMetaTags {
         self.tag(Tag::RequestId as i32).map(|rv| rv.as_i64())
     }
     fn try_request_id(&self) -> crate::Result<RqId> {
-        self.request_id().ok_or_else(|| "Request id not exists.".into())
+        self.request_id().ok_or_else(|| Error::InvalidMessage("Request id not exists.".into()))
     }
     fn set_request_id(&mut self, id: RqId) -> &mut Self::Target {
         self.set_tag(Tag::RequestId as i32, Some(RpcValue::from(id)))
@@ -260,7 +265,7 @@ pub trait RpcMessageMetaTags {
             return self.set_tag(Tag::CallerIds as i32, Some(RpcValue::from(ids[0] as CliId)));
         }
         let lst: List = ids.iter().map(|v| RpcValue::from(*v)).collect();
-        return self.set_tag(Tag::CallerIds as i32, Some(RpcValue::from(lst)));
+        self.set_tag(Tag::CallerIds as i32, Some(RpcValue::from(lst)))
     }
 
     fn push_caller_id(&mut self, id: CliId) -> &mut Self::Target {
@@ -429,16 +434,14 @@ impl std::error::Error for RpcError {
 }
 impl fmt::Display for RpcMessage {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", self.as_rpcvalue().to_cpon())
+        write!(f, "{}", redact(self.as_rpcvalue()).to_cpon())
     }
 }
-/*
 impl fmt::Debug for RpcMessage {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", self.as_rpcvalue().to_cpon())
+        write!(f, "{}", redact(self.as_rpcvalue()).to_cpon())
     }
 }
-*/
 impl Serialize for RpcMessage {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
         where
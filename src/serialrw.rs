@@ -1,21 +1,32 @@
-use std::io::{BufReader};
+use std::collections::VecDeque;
+use std::pin::pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
 use async_trait::async_trait;
 use crc::CRC_32_ISO_HDLC;
 use crate::rpcframe::{Protocol, RpcFrame};
+use futures::future::{select, Either};
 use futures::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use futures_timer::Delay;
 use log::*;
-use shvproto::{ChainPackReader, Reader};
-use crate::framerw::{FrameReader, FrameWriter, serialize_meta};
+use shvproto::MetaMap;
+#[cfg(feature = "tokio")]
+use tokio_util::compat::{Compat, TokioAsyncReadCompatExt, TokioAsyncWriteCompatExt};
+use crate::Error;
+use crate::framerw::{DEFAULT_MAX_FRAME_SIZE, FrameDecoder, FrameReader, FrameWriter, parse_frame_data, serialize_frame};
 
-const STX: u8 = 0xA2;
-const ETX: u8 = 0xA3;
-const ATX: u8 = 0xA4;
-const ESC: u8 = 0xAA;
+pub(crate) const STX: u8 = 0xA2;
+pub(crate) const ETX: u8 = 0xA3;
+pub(crate) const ATX: u8 = 0xA4;
+pub(crate) const ESC: u8 = 0xAA;
 
 const ESTX: u8 = 0x02;
 const EETX: u8 = 0x03;
 const EATX: u8 = 0x04;
 const EESC: u8 = 0x0A;
+
+const READ_BUFFER_SIZE: usize = 8 * 1024;
 pub enum Byte {
     Data(u8),
     Stx,
@@ -23,135 +34,111 @@ pub enum Byte {
     Atx,
     FramingError(u8),
 }
+/// Counters of the frames received by [`SerialFrameReader`] or [`SerialFrameDecoder`].
+#[derive(Debug, Default)]
+pub struct SerialStats {
+    frames: AtomicU64,
+    crc_errors: AtomicU64,
+    framing_errors: AtomicU64,
+    bad_escapes: AtomicU64,
+    timeouts: AtomicU64,
+    discarded_frames: AtomicU64,
+}
+impl SerialStats {
+    /// Frames received successfully.
+    pub fn frames(&self) -> u64 {
+        self.frames.load(Ordering::Relaxed)
+    }
+    pub fn crc_errors(&self) -> u64 {
+        self.crc_errors.load(Ordering::Relaxed)
+    }
+    /// Unexpected STX or ETX and frames with invalid content.
+    pub fn framing_errors(&self) -> u64 {
+        self.framing_errors.load(Ordering::Relaxed)
+    }
+    /// Invalid bytes following ESC.
+    pub fn bad_escapes(&self) -> u64 {
+        self.bad_escapes.load(Ordering::Relaxed)
+    }
+    /// Frames dropped due to the inter-byte timeout.
+    pub fn timeouts(&self) -> u64 {
+        self.timeouts.load(Ordering::Relaxed)
+    }
+    /// Started frames not delivered for any reason, including ATX aborts and oversized frames.
+    pub fn discarded_frames(&self) -> u64 {
+        self.discarded_frames.load(Ordering::Relaxed)
+    }
+}
+
 pub struct SerialFrameReader<R: AsyncRead + Unpin + Send> {
     reader: R,
-    with_crc: bool,
+    buffer: Box<[u8]>,
+    // Partially received frame is kept in the decoder to make receive_frame() cancel safe
+    decoder: SerialFrameDecoder,
+    inter_byte_timeout: Option<Duration>,
 }
 impl<R: AsyncRead + Unpin + Send> SerialFrameReader<R> {
     pub fn new(reader: R) -> Self {
         Self {
             reader,
-            with_crc: false,
+            buffer: vec![0u8; READ_BUFFER_SIZE].into_boxed_slice(),
+            decoder: SerialFrameDecoder::new(),
+            inter_byte_timeout: None,
         }
     }
     pub fn with_crc_check(mut self, on: bool) -> Self {
-        self.with_crc = on;
+        self.decoder = self.decoder.with_crc_check(on);
         self
     }
-    async fn get_byte(&mut self) -> crate::Result<u8> {
-        let mut buff = [0u8; 1];
-        let n = self.reader.read(&mut buff[..]).await?;
-        if n == 0 {
-            Err("End of stream".into())
-        } else {
-            Ok(buff[0])
-        }
+    /// Frames longer than `max_frame_size` are dropped.
+    pub fn with_max_frame_size(mut self, max_frame_size: usize) -> Self {
+        self.decoder = self.decoder.with_max_frame_size(max_frame_size);
+        self
     }
-    async fn get_escaped_byte(&mut self) -> crate::Result<crate::serialrw::Byte> {
-        match self.get_byte().await? {
-            STX => Ok(crate::serialrw::Byte::Stx),
-            ETX => Ok(crate::serialrw::Byte::Etx),
-            ATX => Ok(crate::serialrw::Byte::Atx),
-            ESC => {
-                match self.get_byte().await? {
-                    ESTX => Ok(crate::serialrw::Byte::Data(STX)),
-                    EETX => Ok(crate::serialrw::Byte::Data(ETX)),
-                    EATX => Ok(crate::serialrw::Byte::Data(ATX)),
-                    EESC => Ok(crate::serialrw::Byte::Data(ESC)),
-                    b => {
-                        warn!("Framing error, invalid escape byte {}", b);
-                        Ok(crate::serialrw::Byte::FramingError(b))
-                    }
-                }
-            }
-            b => Ok(crate::serialrw::Byte::Data(b))
-        }
+    /// A partially received frame is dropped if no byte arrives within `timeout`.
+    pub fn with_inter_byte_timeout(mut self, timeout: Duration) -> Self {
+        self.inter_byte_timeout = Some(timeout);
+        self
     }
-    #[cfg(all(test, feature = "async-std"))]
-    async fn read_escaped(&mut self) -> crate::Result<Vec<u8>> {
-        let mut data: Vec<u8> = Default::default();
-        while let Ok(b) = self.get_escaped_byte().await {
-            match b {
-                Byte::Data(b) => { data.push(b) }
-                Byte::Stx => { data.push( STX) }
-                Byte::Etx => { data.push( ETX ) }
-                Byte::Atx => { data.push( ATX ) }
-                Byte::FramingError(b) => { return Err(format!("Framing error, invalid character {b}").into()) }
-            }
-        }
-        Ok(data)
+    /// Line health counters, they can be queried while the reader is in use.
+    pub fn stats(&self) -> Arc<SerialStats> {
+        self.decoder.stats()
+    }
+}
+#[cfg(feature = "tokio")]
+impl<R: tokio::io::AsyncRead + Unpin + Send> SerialFrameReader<Compat<R>> {
+    pub fn from_tokio(reader: R) -> Self {
+        Self::new(reader.compat())
     }
 }
 #[async_trait]
 impl<R: AsyncRead + Unpin + Send> FrameReader for SerialFrameReader<R> {
+    /// This method is cancel safe, a partially received frame is kept in the reader
+    /// and the reception continues with the next call.
     async fn receive_frame(&mut self) -> crate::Result<RpcFrame> {
-        let mut has_stx = false;
-        'read_frame: loop {
-            if !has_stx {
-                loop {
-                    match self.get_escaped_byte().await? {
-                        Byte::Stx => { break }
-                        _ => { continue }
-                    }
-                }
-            }
-            has_stx = false;
-            let mut data: Vec<u8> = vec![];
-            loop {
-                match self.get_escaped_byte().await? {
-                    Byte::Stx => {
-                        has_stx = true;
-                        continue 'read_frame
-                    }
-                    Byte::Data(b) => { data.push(b) }
-                    Byte::Etx => { break }
-                    _ => { continue 'read_frame }
-                }
+        loop {
+            if let Some(frame) = self.decoder.next_frame()? {
+                return Ok(frame)
             }
-            if self.with_crc {
-                let mut crc_data = [0u8; 4];
-                for crc_b in &mut crc_data {
-                    match self.get_escaped_byte().await? {
-                        Byte::Stx => {
-                            has_stx = true;
-                            continue 'read_frame
+            // Nothing is consumed until the read completes, so it can be cancelled
+            let n = match self.inter_byte_timeout.filter(|_| self.decoder.has_partial_frame()) {
+                Some(timeout) => {
+                    match select(pin!(self.reader.read(&mut self.buffer)), Delay::new(timeout)).await {
+                        Either::Left((n, _)) => { n? }
+                        Either::Right(_) => {
+                            log!(target: "Serial", Level::Debug, "Inter-byte timeout, frame dropped");
+                            self.decoder.stats.timeouts.fetch_add(1, Ordering::Relaxed);
+                            self.decoder.discard_frame();
+                            continue
                         }
-                        Byte::Data(b) => { *crc_b = b }
-                        _ => { continue 'read_frame }
                     }
                 }
-                fn as_u32_be(array: &[u8; 4]) -> u32 {
-                    ((array[0] as u32) << 24) +
-                        ((array[1] as u32) << 16) +
-                        ((array[2] as u32) <<  8) +
-                        (array[3] as u32)
-                }
-                let crc1 = as_u32_be(&crc_data);
-                let gen = crc::Crc::<u32>::new(&CRC_32_ISO_HDLC);
-                let crc2 = gen.checksum(&data);
-                //println!("CRC2 {crc2}");
-                if crc1 != crc2 {
-                    log!(target: "Serial", Level::Debug, "CRC error");
-                    continue 'read_frame
-                }
-            }
-            let protocol = data[0];
-            if protocol != Protocol::ChainPack as u8 {
-                log!(target: "Serial", Level::Debug, "Not chainpack message");
-                continue 'read_frame
-            }
-            let mut buffrd = BufReader::new(&data[1 ..]);
-            let mut rd = ChainPackReader::new(&mut buffrd);
-            if let Ok(Some(meta)) = rd.try_read_meta() {
-                let pos = rd.position() + 1;
-                let data: Vec<_> = data.drain(pos .. ).collect();
-                let frame  = RpcFrame { protocol: Protocol::ChainPack, meta, data };
-                log!(target: "RpcMsg", Level::Debug, "R==> {}", &frame);
-                return Ok(frame)
-            } else {
-                log!(target: "Serial", Level::Debug, "Meta data read error");
-                continue 'read_frame
+                None => { self.reader.read(&mut self.buffer).await? }
+            };
+            if n == 0 {
+                return Err(Error::UnexpectedEof)
             }
+            self.decoder.push(&self.buffer[.. n]);
         }
     }
 }
@@ -159,68 +146,85 @@ impl<R: AsyncRead + Unpin + Send> FrameReader for SerialFrameReader<R> {
 pub struct SerialFrameWriter<W: AsyncWrite + Unpin + Send> {
     writer: W,
     with_crc: bool,
+    // A frame was started and neither finished nor aborted, it is aborted before the next one
+    in_frame: bool,
 }
 impl<W: AsyncWrite + Unpin + Send> SerialFrameWriter<W> {
     pub fn new(writer: W) -> Self {
         Self {
             writer,
             with_crc: false,
+            in_frame: false,
         }
     }
     pub fn with_crc_check(mut self, on: bool) -> Self {
         self.with_crc = on;
         self
     }
-    async fn write_bytes(&mut self, digest: &mut Option<crc::Digest<'_, u32>>, data: &[u8]) -> crate::Result<()> {
-        if let Some(ref mut digest) = digest {
-            digest.update(data);
-        }
+    async fn write_bytes(&mut self, data: &[u8]) -> crate::Result<()> {
         self.writer.write_all(data).await?;
         Ok(())
     }
+    // CRC is computed from the escaped data, as it is sent on the wire
     async fn write_escaped(&mut self, digest: &mut Option<crc::Digest<'_, u32>>, data: &[u8]) -> crate::Result<()> {
-        for b in data {
-            match *b {
-                STX => { self.write_bytes(digest, &[ESC, ESTX]).await? }
-                ETX => { self.write_bytes(digest, &[ESC, EETX]).await? }
-                ATX => { self.write_bytes(digest, &[ESC, EATX]).await? }
-                ESC => { self.write_bytes(digest, &[ESC, EESC]).await? }
-                b => { self.write_bytes(digest, &[b]).await? }
-            };
+        let mut buff = Vec::with_capacity(data.len());
+        escape_into(&mut buff, data);
+        if let Some(ref mut digest) = digest {
+            digest.update(&buff);
+        }
+        self.write_bytes(&buff).await
+    }
+    async fn abort_unfinished_frame(&mut self) -> crate::Result<()> {
+        if self.in_frame {
+            log!(target: "Serial", Level::Debug, "Aborting unfinished frame");
+            self.write_bytes(&[ATX]).await?;
+            self.in_frame = false;
         }
         Ok(())
     }
+    /// Starts sending a frame incrementally, the message data is written by
+    /// [`SerialFrameSender::write`] in as many chunks as needed.
+    ///
+    /// The frame is sent by [`SerialFrameSender::finish`] or abandoned with ATX by
+    /// [`SerialFrameSender::abort`]. If the sender is dropped unfinished, for example when the
+    /// sending future is cancelled, the frame is aborted before the next one is sent.
+    ///
+    /// The JSON protocol is not supported, its meta cannot be sent ahead of the data.
+    pub async fn begin_frame(&mut self, protocol: Protocol, meta: &MetaMap) -> crate::Result<SerialFrameSender<'_, W>> {
+        if matches!(protocol, Protocol::Json | Protocol::ResetSession) {
+            return Err(Error::UnsupportedProtocol(protocol.into()));
+        }
+        log!(target: "RpcMsg", Level::Debug, "S<== {} [ streamed data ]", meta);
+        let header = RpcFrame { protocol, meta: meta.clone(), data: vec![] };
+        let header = serialize_frame(&header)?;
+        self.abort_unfinished_frame().await?;
+        self.in_frame = true;
+        self.write_bytes(&[STX]).await?;
+        let mut sender = SerialFrameSender {
+            digest: self.with_crc.then(|| CRC_32.digest()),
+            writer: self,
+        };
+        sender.write(&header).await?;
+        Ok(sender)
+    }
+}
+#[cfg(feature = "tokio")]
+impl<W: tokio::io::AsyncWrite + Unpin + Send> SerialFrameWriter<Compat<W>> {
+    pub fn from_tokio(writer: W) -> Self {
+        Self::new(writer.compat_write())
+    }
 }
 #[async_trait]
 impl<W: AsyncWrite + Unpin + Send> FrameWriter for SerialFrameWriter<W> {
     async fn send_frame(&mut self, frame: RpcFrame) -> crate::Result<()> {
         log!(target: "RpcMsg", Level::Debug, "S<== {}", &frame);
-        let gen = crc::Crc::<u32>::new(&CRC_32_ISO_HDLC);
-        let mut digest = if self.with_crc {
-            Some(gen.digest())
-        } else {
-            None
-        };
-        let meta_data = serialize_meta(&frame)?;
-        self.writer.write_all(&[STX]).await?;
-        let protocol = [Protocol::ChainPack as u8];
-        self.write_escaped(&mut digest, &protocol).await?;
-        self.write_escaped(&mut digest, &meta_data).await?;
-        self.write_escaped(&mut digest, &frame.data).await?;
-        self.writer.write_all(&[ETX]).await?;
-        if self.with_crc {
-            fn u32_to_bytes(x:u32) -> [u8;4] {
-                let b0 : u8 = ((x >> 24) & 0xff) as u8;
-                let b1 : u8 = ((x >> 16) & 0xff) as u8;
-                let b2 : u8 = ((x >> 8) & 0xff) as u8;
-                let b3 : u8 = (x & 0xff) as u8;
-                [b0, b1, b2, b3]
-            }
-            let crc = digest.expect("digest should be some here").finalize();
-            //println!("CRC1 {crc}");
-            let crc_bytes = u32_to_bytes(crc);
-            self.write_escaped(&mut None, &crc_bytes).await?;
-        }
+        let mut buff = Vec::new();
+        write_frame(&mut buff, frame, self.with_crc)?;
+        self.abort_unfinished_frame().await?;
+        // Cancelled in the middle of the frame, it gets aborted by the next one
+        self.in_frame = true;
+        self.write_bytes(&buff).await?;
+        self.in_frame = false;
         // Ensure the encoded frame is written to the socket. The calls above
         // are to the buffered stream and writes. Calling `flush` writes the
         // remaining contents of the buffer to the socket.
@@ -229,11 +233,285 @@ impl<W: AsyncWrite + Unpin + Send> FrameWriter for SerialFrameWriter<W> {
     }
 }
 
+/// Frame being sent by [`SerialFrameWriter::begin_frame`].
+pub struct SerialFrameSender<'a, W: AsyncWrite + Unpin + Send> {
+    writer: &'a mut SerialFrameWriter<W>,
+    digest: Option<crc::Digest<'static, u32>>,
+}
+impl<W: AsyncWrite + Unpin + Send> SerialFrameSender<'_, W> {
+    /// Writes the next chunk of the message data.
+    ///
+    /// If the write fails or is cancelled, the frame can only be aborted.
+    pub async fn write(&mut self, data: &[u8]) -> crate::Result<()> {
+        self.writer.write_escaped(&mut self.digest, data).await
+    }
+    pub async fn finish(mut self) -> crate::Result<()> {
+        self.writer.write_bytes(&[ETX]).await?;
+        if let Some(digest) = self.digest.take() {
+            self.writer.write_escaped(&mut None, &digest.finalize().to_be_bytes()).await?;
+        }
+        self.writer.in_frame = false;
+        self.writer.writer.flush().await?;
+        Ok(())
+    }
+    /// Abandons the frame, the receiver drops the data received so far.
+    pub async fn abort(self) -> crate::Result<()> {
+        self.writer.abort_unfinished_frame().await?;
+        self.writer.writer.flush().await?;
+        Ok(())
+    }
+}
+
+fn escape_sequence(b: u8) -> Option<[u8; 2]> {
+    match b {
+        STX => { Some([ESC, ESTX]) }
+        ETX => { Some([ESC, EETX]) }
+        ATX => { Some([ESC, EATX]) }
+        ESC => { Some([ESC, EESC]) }
+        _ => { None }
+    }
+}
+
+fn escape_into(buff: &mut Vec<u8>, data: &[u8]) {
+    for b in data {
+        match escape_sequence(*b) {
+            Some(seq) => { buff.extend_from_slice(&seq) }
+            None => { buff.push(*b) }
+        }
+    }
+}
+
+// The SHV serial transport computes the CRC32 from the escaped frame data between STX and ETX,
+// as it is sent on the wire. The CRC is sent escaped after ETX.
+static CRC_32: crc::Crc<u32> = crc::Crc::<u32>::new(&CRC_32_ISO_HDLC);
+
+/// CRC of the escaped `data`, without escaping it to a buffer.
+fn escaped_crc32(mut data: &[u8]) -> u32 {
+    let mut digest = CRC_32.digest();
+    while let Some((n, seq)) = data.iter().enumerate().find_map(|(n, b)| escape_sequence(*b).map(|seq| (n, seq))) {
+        digest.update(&data[.. n]);
+        digest.update(&seq);
+        data = &data[n + 1 ..];
+    }
+    digest.update(data);
+    digest.finalize()
+}
+
+#[derive(Clone, Copy)]
+enum ReadState {
+    WaitStx,
+    Data,
+    // Number of CRC bytes received
+    Crc(usize),
+}
+
+/// Decodes STX/ETX delimited frames.
+///
+/// Pushed bytes are unescaped as they arrive, so each byte is processed once no matter
+/// how the frames are split into chunks. Corrupted frames are dropped and decoding
+/// resumes on the next STX.
+pub struct SerialFrameDecoder {
+    with_crc: bool,
+    max_frame_size: usize,
+    // Partially received frame
+    state: ReadState,
+    escape: bool,
+    data: Vec<u8>,
+    crc: [u8; 4],
+    frames: VecDeque<RpcFrame>,
+    stats: Arc<SerialStats>,
+}
+impl Default for SerialFrameDecoder {
+    fn default() -> Self {
+        Self {
+            with_crc: false,
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            state: ReadState::WaitStx,
+            escape: false,
+            data: vec![],
+            crc: [0; 4],
+            frames: VecDeque::new(),
+            stats: Default::default(),
+        }
+    }
+}
+impl SerialFrameDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn with_crc_check(mut self, on: bool) -> Self {
+        self.with_crc = on;
+        self
+    }
+    /// Frames longer than `max_frame_size` are dropped.
+    pub fn with_max_frame_size(mut self, max_frame_size: usize) -> Self {
+        self.max_frame_size = max_frame_size;
+        self
+    }
+    /// Line health counters, they can be queried while the decoder is in use.
+    pub fn stats(&self) -> Arc<SerialStats> {
+        self.stats.clone()
+    }
+    fn unescape_byte(&mut self, b: u8) -> Option<Byte> {
+        if !self.escape {
+            return match b {
+                STX => { Some(Byte::Stx) }
+                ETX => { Some(Byte::Etx) }
+                ATX => { Some(Byte::Atx) }
+                ESC => {
+                    self.escape = true;
+                    None
+                }
+                b => { Some(Byte::Data(b)) }
+            }
+        }
+        self.escape = false;
+        match b {
+            ESTX => Some(Byte::Data(STX)),
+            EETX => Some(Byte::Data(ETX)),
+            EATX => Some(Byte::Data(ATX)),
+            EESC => Some(Byte::Data(ESC)),
+            b => {
+                warn!("Framing error, invalid escape byte {}", b);
+                self.stats.bad_escapes.fetch_add(1, Ordering::Relaxed);
+                Some(Byte::FramingError(b))
+            }
+        }
+    }
+    /// Moves the frame data up to the next control or escape byte at once, returns the number of bytes taken.
+    fn take_data_run(&mut self, data: &[u8]) -> usize {
+        if self.escape {
+            return 0
+        }
+        let n = data.iter().position(|b| matches!(*b, STX | ETX | ATX | ESC)).unwrap_or(data.len());
+        // Overflowing byte is left to process_byte() to drop the frame
+        let n = n.min(self.max_frame_size.saturating_sub(self.data.len()));
+        self.data.extend_from_slice(&data[.. n]);
+        n
+    }
+    fn take_frame(&mut self) {
+        self.state = ReadState::WaitStx;
+        match parse_frame_data(std::mem::take(&mut self.data)) {
+            Ok(frame) => {
+                log!(target: "RpcMsg", Level::Debug, "R==> {}", &frame);
+                self.stats.frames.fetch_add(1, Ordering::Relaxed);
+                self.frames.push_back(frame);
+            }
+            Err(err) => {
+                log!(target: "Serial", Level::Debug, "{err}");
+                self.stats.framing_errors.fetch_add(1, Ordering::Relaxed);
+                self.stats.discarded_frames.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+    fn discard_frame(&mut self) {
+        self.stats.discarded_frames.fetch_add(1, Ordering::Relaxed);
+        self.data.clear();
+        self.escape = false;
+        self.state = ReadState::WaitStx;
+    }
+    fn process_byte(&mut self, byte: Byte) {
+        match (self.state, byte) {
+            (ReadState::WaitStx, Byte::Stx) => {
+                self.data.clear();
+                self.state = ReadState::Data;
+            }
+            (_, Byte::Stx) => {
+                log!(target: "Serial", Level::Debug, "Framing error, STX inside frame");
+                self.stats.framing_errors.fetch_add(1, Ordering::Relaxed);
+                self.discard_frame();
+                self.state = ReadState::Data;
+            }
+            (ReadState::WaitStx, _) => { }
+            (ReadState::Data, Byte::Data(b)) => {
+                if self.data.len() == self.max_frame_size {
+                    log!(target: "Serial", Level::Debug, "Frame exceeds the limit of {} bytes, dropped", self.max_frame_size);
+                    self.discard_frame();
+                } else {
+                    self.data.push(b);
+                }
+            }
+            (ReadState::Data, Byte::Etx) => {
+                if self.with_crc {
+                    self.state = ReadState::Crc(0);
+                } else {
+                    self.take_frame();
+                }
+            }
+            (ReadState::Crc(n), Byte::Data(b)) => {
+                self.crc[n] = b;
+                if n + 1 < self.crc.len() {
+                    self.state = ReadState::Crc(n + 1);
+                } else if u32::from_be_bytes(self.crc) != escaped_crc32(&self.data) {
+                    log!(target: "Serial", Level::Debug, "CRC error");
+                    self.stats.crc_errors.fetch_add(1, Ordering::Relaxed);
+                    self.discard_frame();
+                } else {
+                    self.take_frame();
+                }
+            }
+            (_, Byte::Atx) => {
+                log!(target: "Serial", Level::Debug, "Frame aborted by ATX");
+                self.discard_frame();
+            }
+            (_, Byte::FramingError(_)) => {
+                // counted as a bad escape already
+                self.discard_frame();
+            }
+            (_, Byte::Etx) => {
+                log!(target: "Serial", Level::Debug, "Framing error, unexpected ETX");
+                self.stats.framing_errors.fetch_add(1, Ordering::Relaxed);
+                self.discard_frame();
+            }
+        }
+    }
+}
+impl FrameDecoder for SerialFrameDecoder {
+    fn push(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            if let ReadState::Data = self.state {
+                data = &data[self.take_data_run(data) ..];
+            }
+            let Some((b, rest)) = data.split_first() else {
+                break
+            };
+            data = rest;
+            if let Some(byte) = self.unescape_byte(*b) {
+                self.process_byte(byte);
+            }
+        }
+    }
+    fn next_frame(&mut self) -> crate::Result<Option<RpcFrame>> {
+        Ok(self.frames.pop_front())
+    }
+    fn has_partial_frame(&self) -> bool {
+        self.escape || !matches!(self.state, ReadState::WaitStx)
+    }
+}
+
+pub fn write_frame(buff: &mut Vec<u8>, frame: RpcFrame, with_crc: bool) -> crate::Result<()> {
+    let data = serialize_frame(&frame)?;
+    buff.push(STX);
+    let start = buff.len();
+    escape_into(buff, &data);
+    let crc = with_crc.then(|| CRC_32.checksum(&buff[start ..]));
+    buff.push(ETX);
+    if let Some(crc) = crc {
+        escape_into(buff, &crc.to_be_bytes());
+    }
+    Ok(())
+}
+
 #[cfg(all(test, feature = "async-std"))]
 mod test {
     use async_std::io::BufWriter;
+    use shvproto::RpcValue;
+    use shvproto::MetaMap;
     use crate::RpcMessage;
+    use crate::rpcframe::Protocol;
     use crate::util::{hex_array, hex_dump};
+    use crate::framerw::serialize_meta;
+    use crate::testutil::{receive_frame_cancelled, ChunkedReader};
     use super::*;
     #[async_std::test]
     async fn test_write_bytes() {
@@ -255,9 +533,12 @@ mod test {
                 assert_eq!(&buff, esc_data);
             }
             {
-                let buffrd = async_std::io::BufReader::new(&*buff);
-                let mut rd = SerialFrameReader::new(buffrd);
-                let read_data = rd.read_escaped().await.unwrap();
+                let mut decoder = SerialFrameDecoder::new();
+                let read_data: Vec<u8> = buff.iter().filter_map(|b| match decoder.unescape_byte(*b) {
+                    Some(Byte::Data(b)) => { Some(b) }
+                    Some(_) => { panic!("invalid escape sequence") }
+                    None => { None }
+                }).collect();
                 assert_eq!(&read_data, data);
             }
         }
@@ -293,4 +574,294 @@ mod test {
             }
         }
     }
+
+    #[async_std::test]
+    async fn test_crc_wire_format() {
+        // CRC32 of the escaped data between STX and ETX
+        let frame = RpcFrame::new(Protocol::ChainPack, MetaMap::new(), vec![STX, 0x42, ESC]);
+        let wire = [STX, 0x01, 0x8b, 0xff, ESC, ESTX, 0x42, ESC, EESC, ETX, 0x90, 0xe0, 0x61, 0x5c];
+        let mut buff = vec![];
+        write_frame(&mut buff, frame.clone(), true).unwrap();
+        assert_eq!(buff, wire);
+        let mut buff = vec![];
+        {
+            let mut wr = SerialFrameWriter::new(&mut buff).with_crc_check(true);
+            wr.send_frame(frame.clone()).await.unwrap();
+            let mut sender = wr.begin_frame(frame.protocol, &frame.meta).await.unwrap();
+            sender.write(&frame.data[.. 1]).await.unwrap();
+            sender.write(&frame.data[1 ..]).await.unwrap();
+            sender.finish().await.unwrap();
+        }
+        assert_eq!(buff, [wire, wire].concat());
+        let mut decoder = SerialFrameDecoder::new().with_crc_check(true);
+        decoder.push(&wire);
+        assert_eq!(decoder.next_frame().unwrap(), Some(frame));
+        assert_eq!(decoder.stats().crc_errors(), 0);
+    }
+
+    #[test]
+    fn test_frame_decoder() {
+        let frame = RpcMessage::new_request("foo/bar", "baz", Some(RpcValue::from(vec![STX, ETX, ATX, ESC]))).to_frame().unwrap();
+        for with_crc in [false, true] {
+            let mut buff = b"garbage".to_vec();
+            for _ in 0 .. 2 {
+                buff.extend_from_slice(&[STX, ESC, 1, ETX, ATX]);
+                write_frame(&mut buff, frame.clone(), with_crc).unwrap();
+            }
+            let mut decoder = SerialFrameDecoder::new().with_crc_check(with_crc);
+            let mut frames = vec![];
+            for b in &buff {
+                decoder.push(&[*b]);
+                while let Some(frame) = decoder.next_frame().unwrap() {
+                    frames.push(frame);
+                }
+            }
+            assert_eq!(frames, vec![frame.clone(), frame.clone()]);
+        }
+    }
+
+    #[test]
+    fn test_frame_decoder_large_frame() {
+        let frame = RpcMessage::new_request("foo/bar", "baz", Some(RpcValue::from([0x55u8; 1024].repeat(8 * 1024)))).to_frame().unwrap();
+        for with_crc in [false, true] {
+            let mut buff = vec![];
+            write_frame(&mut buff, frame.clone(), with_crc).unwrap();
+            let mut decoder = SerialFrameDecoder::new().with_crc_check(with_crc);
+            let start = std::time::Instant::now();
+            let mut frames = vec![];
+            for chunk in buff.chunks(4 * 1024) {
+                decoder.push(chunk);
+                while let Some(frame) = decoder.next_frame().unwrap() {
+                    frames.push(frame);
+                }
+            }
+            // Rescanning the buffered data on each push takes minutes here
+            assert!(start.elapsed() < Duration::from_secs(5), "elapsed: {:?}", start.elapsed());
+            assert_eq!(frames, vec![frame.clone()]);
+            assert!(!decoder.has_partial_frame());
+        }
+    }
+
+    #[async_std::test]
+    async fn test_max_frame_size() {
+        let small = RpcMessage::new_request("foo/bar", "baz", None).to_frame().unwrap();
+        let large = RpcMessage::new_request("foo/bar", "baz", Some(RpcValue::from(vec![0u8; 1000]))).to_frame().unwrap();
+        let mut buff = vec![];
+        for frame in [&large, &small] {
+            write_frame(&mut buff, frame.clone(), false).unwrap();
+        }
+        let mut rd = SerialFrameReader::new(&*buff).with_max_frame_size(100);
+        assert_eq!(rd.receive_frame().await.unwrap(), small);
+        let mut decoder = SerialFrameDecoder::new().with_max_frame_size(100);
+        decoder.push(&buff);
+        assert_eq!(decoder.next_frame().unwrap(), Some(small));
+    }
+
+    #[async_std::test]
+    async fn test_reset_session() {
+        for with_crc in [false, true] {
+            let mut buff = vec![];
+            SerialFrameWriter::new(&mut buff).with_crc_check(with_crc).send_reset_session().await.unwrap();
+            assert_eq!(&buff[.. 3], [STX, 0, ETX]);
+            let mut rd = SerialFrameReader::new(&*buff).with_crc_check(with_crc);
+            assert!(rd.receive_frame().await.unwrap().is_reset_session());
+            let mut decoder = SerialFrameDecoder::new().with_crc_check(with_crc);
+            decoder.push(&buff);
+            assert!(decoder.next_frame().unwrap().unwrap().is_reset_session());
+        }
+    }
+
+    #[test]
+    fn test_cancel_safety() {
+        let frames: Vec<_> = [
+            RpcMessage::new_request("foo/bar", "baz", Some("hello".into())),
+            RpcMessage::new_request("test", "ping", Some(RpcValue::from(vec![STX, ETX, ATX, ESC]))),
+        ].iter().map(|msg| msg.to_frame().unwrap()).collect();
+        for with_crc in [false, true] {
+            let mut data = vec![];
+            for frame in &frames {
+                write_frame(&mut data, frame.clone(), with_crc).unwrap();
+            }
+            let mut rd = SerialFrameReader::new(ChunkedReader::interrupted(data, usize::MAX)).with_crc_check(with_crc);
+            for frame in &frames {
+                assert_eq!(&receive_frame_cancelled(&mut rd).unwrap(), frame);
+            }
+        }
+    }
+
+    #[async_std::test]
+    async fn test_buffered_read() {
+        let large = RpcMessage::new_request("foo/bar", "baz", Some(RpcValue::from([0x55u8, STX, ESC].repeat(READ_BUFFER_SIZE)))).to_frame().unwrap();
+        let small = RpcMessage::new_signal("foo/bar", "chng", Some(1.into())).to_frame().unwrap();
+        let mut buff = vec![];
+        for frame in [&large, &small, &large] {
+            write_frame(&mut buff, frame.clone(), true).unwrap();
+        }
+        let mut rd = SerialFrameReader::new(ChunkedReader::new(buff.clone(), usize::MAX)).with_crc_check(true);
+        for frame in [&large, &small, &large] {
+            assert_eq!(&rd.receive_frame().await.unwrap(), frame);
+        }
+        assert!(rd.reader.reads <= buff.len() / READ_BUFFER_SIZE + 1, "reads: {}", rd.reader.reads);
+
+        // Frame data is limited exactly, the protocol byte counts too
+        let data_len = small.data.len() + serialize_meta(&small).unwrap().len() + 1;
+        for (max_frame_size, received) in [(data_len, true), (data_len - 1, false)] {
+            let mut buff = vec![];
+            write_frame(&mut buff, small.clone(), false).unwrap();
+            let mut rd = SerialFrameReader::new(&*buff).with_max_frame_size(max_frame_size);
+            assert_eq!(rd.receive_frame().await.is_ok(), received);
+        }
+    }
+
+    #[async_std::test]
+    async fn test_inter_byte_timeout() {
+        let frame = RpcMessage::new_request("foo/bar", "baz", None).to_frame().unwrap();
+        let mut data = vec![];
+        write_frame(&mut data, frame.clone(), true).unwrap();
+        let (reader, mut writer) = crate::loopback::pipe();
+        let mut rd = SerialFrameReader::new(reader).with_crc_check(true).with_inter_byte_timeout(Duration::from_millis(20));
+        let stats = rd.stats();
+        writer.write_all(&data[.. data.len() / 2]).await.unwrap();
+        let sender = async_std::task::spawn(async move {
+            async_std::task::sleep(Duration::from_millis(100)).await;
+            writer.write_all(&data).await.unwrap();
+        });
+        assert_eq!(rd.receive_frame().await.unwrap(), frame);
+        sender.await;
+        assert_eq!(stats.timeouts(), 1);
+        assert_eq!(stats.discarded_frames(), 1);
+        assert_eq!(stats.framing_errors(), 0);
+        assert_eq!(stats.frames(), 1);
+    }
+
+    #[async_std::test]
+    async fn test_stats() {
+        let frame = RpcMessage::new_request("foo/bar", "baz", Some("hello".into())).to_frame().unwrap();
+        let mut good = vec![];
+        write_frame(&mut good, frame.clone(), true).unwrap();
+        // Corrupt a plain data byte, the CRC bytes depend on the request ID and might be escaped
+        let mut bad_crc = good.clone();
+        let pos = bad_crc.windows(5).position(|w| w == b"hello").unwrap();
+        bad_crc[pos] = b'j';
+        let mut data = vec![];
+        data.extend_from_slice(&bad_crc);
+        data.extend_from_slice(&[STX, 1, ESC, 0x55, 2, ETX]);
+        data.extend_from_slice(&[STX, 1, 2, ATX]);
+        data.extend_from_slice(&[STX, 1, 2, ETX, 3, ETX]);
+        data.extend_from_slice(&good[.. good.len() / 2]);
+        data.extend_from_slice(&good);
+        let mut rd = SerialFrameReader::new(&*data).with_crc_check(true);
+        assert_eq!(rd.receive_frame().await.unwrap(), frame);
+        let stats = rd.stats();
+        assert_eq!(stats.frames(), 1);
+        assert_eq!(stats.crc_errors(), 1);
+        assert_eq!(stats.bad_escapes(), 1);
+        assert_eq!(stats.framing_errors(), 2);
+        assert_eq!(stats.discarded_frames(), 5);
+    }
+
+    #[async_std::test]
+    async fn test_streamed_frame_abort() {
+        let msg = RpcMessage::new_request("test/device", "get", Some(RpcValue::from([STX, ETX, ATX, ESC].repeat(10))));
+        let frame = msg.to_frame().unwrap();
+        let signal = RpcMessage::new_signal("test/device", "chng", Some(1.into())).to_frame().unwrap();
+        for with_crc in [false, true] {
+            let mut buff = vec![];
+            {
+                let mut wr = SerialFrameWriter::new(&mut buff).with_crc_check(with_crc);
+                let mut sender = wr.begin_frame(frame.protocol, &frame.meta).await.unwrap();
+                for chunk in frame.data.chunks(3) {
+                    sender.write(chunk).await.unwrap();
+                }
+                sender.finish().await.unwrap();
+                let mut sender = wr.begin_frame(frame.protocol, &frame.meta).await.unwrap();
+                sender.write(&frame.data[.. 10]).await.unwrap();
+                sender.abort().await.unwrap();
+                {
+                    // Dropped unfinished, aborted by the next frame
+                    let mut sender = wr.begin_frame(frame.protocol, &frame.meta).await.unwrap();
+                    sender.write(&frame.data[.. 10]).await.unwrap();
+                }
+                wr.send_frame(signal.clone()).await.unwrap();
+                assert!(wr.begin_frame(Protocol::Json, &frame.meta).await.is_err());
+            }
+            assert_eq!(buff.iter().filter(|b| **b == ATX).count(), 2);
+
+            let mut rd = SerialFrameReader::new(&*buff).with_crc_check(with_crc);
+            assert_eq!(rd.receive_frame().await.unwrap(), frame);
+            assert_eq!(rd.receive_frame().await.unwrap(), signal);
+            assert!(rd.receive_frame().await.unwrap_err().is_eof());
+            assert_eq!(rd.stats().discarded_frames(), 2);
+            let mut decoder = SerialFrameDecoder::new().with_crc_check(with_crc);
+            decoder.push(&buff);
+            assert_eq!(decoder.next_frame().unwrap().unwrap(), frame);
+            assert_eq!(decoder.next_frame().unwrap().unwrap(), signal);
+            assert!(decoder.next_frame().unwrap().is_none());
+        }
+    }
+
+    #[async_std::test]
+    async fn test_protocols() {
+        let msg = RpcMessage::new_request("test/device", "set", Some(RpcValue::from(vec![RpcValue::from(1), RpcValue::from("foo")])));
+        for with_crc in [false, true] {
+            let mut frames = vec![];
+            for protocol in [Protocol::ChainPack, Protocol::Cpon, Protocol::Json] {
+                frames.push(msg.to_frame_with_protocol(protocol).unwrap());
+            }
+            frames.push(RpcFrame::new(Protocol::Unknown(9), MetaMap::new(), vec![STX, 1, 2]));
+            let mut buff = vec![];
+            let mut wr = SerialFrameWriter::new(&mut buff).with_crc_check(with_crc);
+            for frame in &frames {
+                wr.send_frame(frame.clone()).await.unwrap();
+            }
+            let mut rd = SerialFrameReader::new(&*buff).with_crc_check(with_crc);
+            let mut decoder = SerialFrameDecoder::new().with_crc_check(with_crc);
+            decoder.push(&buff);
+            for frame in &frames {
+                assert_eq!(&rd.receive_frame().await.unwrap(), frame);
+                assert_eq!(&decoder.next_frame().unwrap().unwrap(), frame);
+            }
+            for frame in &frames[.. 3] {
+                assert_eq!(frame.to_rpcmesage().unwrap().to_cpon(), msg.to_cpon());
+            }
+        }
+    }
+
+    proptest::proptest! {
+        #[test]
+        fn test_frame_decoder_random_data(data: Vec<u8>, chunk_size in 1 .. 64usize, with_crc: bool) {
+            let mut decoder = SerialFrameDecoder::new().with_crc_check(with_crc).with_max_frame_size(1024);
+            for chunk in data.chunks(chunk_size) {
+                decoder.push(chunk);
+                while let Ok(Some(frame)) = decoder.next_frame() {
+                    let _ = frame.to_rpcmesage();
+                }
+            }
+        }
+
+        #[test]
+        fn test_frame_reader_random_data(mut data: Vec<u8>, with_crc: bool) {
+            // Make sure there is a frame to read from the random data
+            data.push(STX);
+            data.push(ETX);
+            let mut rd = SerialFrameReader::new(&*data).with_crc_check(with_crc);
+            while futures::executor::block_on(rd.receive_frame()).is_ok() {}
+        }
+
+        #[test]
+        fn test_frame_decoder_round_trip(param: Vec<u8>, chunk_size in 1 .. 64usize, with_crc: bool) {
+            let frame = RpcMessage::new_request("foo/bar", "baz", Some(RpcValue::from(param))).to_frame().unwrap();
+            let mut data = vec![];
+            write_frame(&mut data, frame.clone(), with_crc).unwrap();
+            let mut decoder = SerialFrameDecoder::new().with_crc_check(with_crc);
+            let mut frames = vec![];
+            for chunk in data.chunks(chunk_size) {
+                decoder.push(chunk);
+                while let Some(frame) = decoder.next_frame().unwrap() {
+                    frames.push(frame);
+                }
+            }
+            proptest::prop_assert_eq!(frames, vec![frame]);
+        }
+    }
 }
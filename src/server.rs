@@ -0,0 +1,207 @@
+use std::collections::HashMap;
+use std::time::Duration;
+use log::*;
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use shvproto::RpcValue;
+use crate::client::{LoginType, Password};
+use crate::framerw::{FrameReader, FrameWriter};
+use crate::rpcmessage::{RpcError, RpcErrorCode};
+use crate::util::{sha1_hash, sha1_nonce_hash, sha1_password_hash};
+use crate::{Error, RpcMessage, RpcMessageMetaTags};
+
+pub trait UserStore {
+    fn password(&self, user: &str) -> Option<Password>;
+}
+impl UserStore for HashMap<String, Password> {
+    fn password(&self, user: &str) -> Option<Password> {
+        self.get(user).cloned()
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LoginOptions {
+    pub device_id: Option<String>,
+    pub mount_point: Option<String>,
+    pub idle_watchdog_timeout: Option<Duration>,
+}
+impl LoginOptions {
+    pub fn from_rpcvalue(options: &RpcValue) -> Self {
+        let options = options.as_map();
+        let device = options.get("device").map(RpcValue::as_map);
+        let device_string = |key: &str| {
+            device.and_then(|device| device.get(key))
+                .map(|val| val.as_str().to_string())
+                .filter(|val| !val.is_empty())
+        };
+        let idle_watchdog_timeout = options.get("idleWatchDogTimeOut")
+            .map(RpcValue::as_i64)
+            .filter(|timeout| *timeout > 0)
+            .map(|timeout| Duration::from_secs(timeout as u64));
+        Self {
+            device_id: device_string("deviceId"),
+            mount_point: device_string("mountPoint"),
+            idle_watchdog_timeout,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct AcceptedLogin {
+    pub user: String,
+    pub login_type: LoginType,
+    pub options: LoginOptions,
+}
+
+pub fn check_password(login_type: LoginType, password: &str, nonce: &str, stored_password: &Password) -> bool {
+    let expected = match (login_type, stored_password) {
+        (LoginType::PLAIN, Password::Plain(stored)) => {
+            return password == stored
+        }
+        (LoginType::PLAIN, Password::Sha1(stored)) => {
+            return sha1_hash(password.as_bytes()).eq_ignore_ascii_case(stored.as_bytes())
+        }
+        (LoginType::SHA1, Password::Plain(stored)) => {
+            sha1_password_hash(stored.as_bytes(), nonce.as_bytes())
+        }
+        (LoginType::SHA1, Password::Sha1(stored)) => {
+            sha1_nonce_hash(stored.to_ascii_lowercase().as_bytes(), nonce.as_bytes())
+        }
+    };
+    expected == password.as_bytes()
+}
+
+fn generate_nonce() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(16)
+        .map(char::from)
+        .collect()
+}
+
+async fn receive_request(frame_reader: &mut (dyn FrameReader + Send), method: &str) -> crate::Result<RpcMessage> {
+    loop {
+        let frame = frame_reader.receive_frame().await?;
+        if frame.is_reset_session() {
+            debug!("Reset session received during login");
+            continue
+        }
+        let msg = frame.to_rpcmesage()?;
+        if msg.is_request() && msg.method() == Some(method) {
+            return Ok(msg)
+        }
+        return Err(Error::InvalidMessage(format!("Login protocol violation, expected '{method}' request, got: {msg}")))
+    }
+}
+
+async fn send_response(frame_writer: &mut (dyn FrameWriter + Send), request: &RpcMessage, result: Result<RpcValue, RpcError>) -> crate::Result<()> {
+    let mut resp = request.prepare_response()?;
+    resp.set_result_or_error(result);
+    frame_writer.send_message(resp).await
+}
+
+/// Server side of the login handshake.
+///
+/// Answers `hello` with a random nonce, verifies the `login` request credentials
+/// against `user_store` and assigns `client_id` to the connection.
+pub async fn accept_login(frame_reader: &mut (dyn FrameReader + Send), frame_writer: &mut (dyn FrameWriter + Send), user_store: &(dyn UserStore + Sync), client_id: i32) -> crate::Result<AcceptedLogin>
+{
+    verify_login(frame_reader, frame_writer, user_store).await?
+        .accept(frame_writer, client_id).await
+}
+
+/// Login verified by [`verify_login`], the `login` request is not answered yet.
+pub struct PendingLogin {
+    request: RpcMessage,
+    login: AcceptedLogin,
+}
+impl PendingLogin {
+    pub fn login(&self) -> &AcceptedLogin {
+        &self.login
+    }
+    /// Sends the login response assigning `client_id` to the connection.
+    pub async fn accept(self, frame_writer: &mut (dyn FrameWriter + Send), client_id: i32) -> crate::Result<AcceptedLogin> {
+        let mut result = shvproto::Map::new();
+        result.insert("clientId".into(), RpcValue::from(client_id));
+        send_response(frame_writer, &self.request, Ok(RpcValue::from(result))).await?;
+        Ok(self.login)
+    }
+}
+
+/// First part of [`accept_login`], the handshake up to the verification of the credentials.
+///
+/// Rejected login is answered here, an accepted one by [`PendingLogin::accept`]. This lets
+/// the caller register the client before the peer learns it is logged in.
+pub async fn verify_login(frame_reader: &mut (dyn FrameReader + Send), frame_writer: &mut (dyn FrameWriter + Send), user_store: &(dyn UserStore + Sync)) -> crate::Result<PendingLogin>
+{
+    let rq = receive_request(frame_reader, "hello").await?;
+    let nonce = generate_nonce();
+    let mut result = shvproto::Map::new();
+    result.insert("nonce".into(), RpcValue::from(&nonce));
+    send_response(frame_writer, &rq, Ok(RpcValue::from(result))).await?;
+
+    let rq = receive_request(frame_reader, "login").await?;
+    let params = rq.param().map(RpcValue::as_map).cloned().unwrap_or_default();
+    let login = params.get("login").map(RpcValue::as_map).cloned().unwrap_or_default();
+    let user = login.get("user").map(RpcValue::as_str).unwrap_or_default();
+    let password = login.get("password").map(RpcValue::as_str).unwrap_or_default();
+    let login_type = match login.get("type").map(RpcValue::as_str) {
+        None => { Some(LoginType::SHA1) }
+        Some(login_type) => { LoginType::from_str(login_type) }
+    };
+    let accepted = match (login_type, user_store.password(user)) {
+        (Some(login_type), Some(stored_password)) => {
+            check_password(login_type, password, &nonce, &stored_password)
+        }
+        _ => { false }
+    };
+    let Some(login_type) = login_type.filter(|_| accepted) else {
+        warn!("Login of user '{user}' rejected");
+        let invalid_login = || RpcError::new(RpcErrorCode::PermissionDenied, "Invalid login");
+        send_response(frame_writer, &rq, Err(invalid_login())).await?;
+        return Err(Error::LoginRejected(invalid_login()))
+    };
+    let options = params.get("options").map(LoginOptions::from_rpcvalue).unwrap_or_default();
+    let login = AcceptedLogin {
+        user: user.to_string(),
+        login_type,
+        options,
+    };
+    Ok(PendingLogin { request: rq, login })
+}
+
+#[cfg(test)]
+mod test {
+    use crate::client::LoginParams;
+    use super::*;
+
+    #[test]
+    fn test_check_password() {
+        let nonce = "abcd";
+        let hash = String::from_utf8(sha1_hash(b"secret")).unwrap();
+        for stored in [Password::from("secret"), Password::Sha1(hash.clone()), Password::Sha1(hash.to_uppercase())] {
+            for login_type in [LoginType::PLAIN, LoginType::SHA1] {
+                let params = LoginParams { password: Password::from("secret"), login_type, ..Default::default() };
+                let password = params.login_password(nonce).unwrap();
+                assert!(check_password(login_type, &password, nonce, &stored));
+                assert!(!check_password(login_type, "bad", nonce, &stored));
+            }
+        }
+    }
+
+    #[test]
+    fn test_login_options() {
+        let params = LoginParams {
+            mount_point: "test/device".into(),
+            heartbeat_interval: Duration::from_secs(10),
+            ..Default::default()
+        };
+        let rv = params.to_rpcvalue("").unwrap();
+        let options = LoginOptions::from_rpcvalue(rv.as_map().get("options").unwrap());
+        assert_eq!(options, LoginOptions {
+            device_id: None,
+            mount_point: Some("test/device".into()),
+            idle_watchdog_timeout: Some(Duration::from_secs(30)),
+        });
+    }
+}
@@ -1,100 +1,295 @@
-use std::io::{BufReader};
+use std::collections::VecDeque;
 use async_trait::async_trait;
-use crate::rpcframe::{Protocol, RpcFrame};
+use crate::rpcframe::RpcFrame;
 use futures::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use log::*;
-use shvproto::{ChainPackReader, ChainPackWriter, Reader, ReadError};
-use crate::framerw::{FrameReader, FrameWriter, serialize_meta};
+#[cfg(feature = "tokio")]
+use tokio_util::compat::{Compat, TokioAsyncReadCompatExt, TokioAsyncWriteCompatExt};
+use shvproto::{ChainPackReader, ChainPackWriter};
+use crate::Error;
+use crate::framerw::{DEFAULT_MAX_FRAME_SIZE, FrameDecoder, FrameReader, FrameWriter, parse_frame_data, serialize_frame};
 use shvproto::reader::ReadErrorReason;
 
+const READ_BUFFER_SIZE: usize = 8 * 1024;
+
 pub struct StreamFrameReader<R: AsyncRead + Unpin + Send> {
     reader: R,
+    // Received bytes not consumed yet are buffer[pos .. len]
+    buffer: Box<[u8]>,
+    pos: usize,
+    len: usize,
+    max_frame_size: usize,
+    // Partially received frame data and its fill position, kept here to make receive_frame() cancel safe
+    frame_data: Option<Vec<u8>>,
+    frame_pos: usize,
+    // Remaining bytes of an oversized frame
+    skip: usize,
+    // Frame boundaries are lost after an invalid length prefix
+    framing_error: Option<String>,
 }
 impl<R: AsyncRead + Unpin + Send> StreamFrameReader<R> {
     pub fn new(reader: R) -> Self {
         Self {
-            reader
+            reader,
+            buffer: vec![0u8; READ_BUFFER_SIZE].into_boxed_slice(),
+            pos: 0,
+            len: 0,
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            frame_data: None,
+            frame_pos: 0,
+            skip: 0,
+            framing_error: None,
         }
     }
-    async fn get_byte(&mut self) -> crate::Result<u8> {
-        let mut buff = [0u8; 1];
-        let n = self.reader.read(&mut buff[..]).await?;
+    /// Frames longer than `max_frame_size` are skipped and reported by [`Error::FrameTooLarge`].
+    pub fn with_max_frame_size(mut self, max_frame_size: usize) -> Self {
+        self.max_frame_size = max_frame_size;
+        self
+    }
+    fn buffered(&self) -> &[u8] {
+        &self.buffer[self.pos .. self.len]
+    }
+    async fn fill_buffer(&mut self) -> crate::Result<()> {
+        if self.pos > 0 {
+            self.buffer.copy_within(self.pos .. self.len, 0);
+            self.len -= self.pos;
+            self.pos = 0;
+        }
+        let n = self.reader.read(&mut self.buffer[self.len ..]).await?;
         if n == 0 {
-            Err("Unexpected end of stream".into())
-        } else {
-            Ok(buff[0])
+            return Err(Error::UnexpectedEof);
+        }
+        self.len += n;
+        Ok(())
+    }
+    async fn read_frame_data(&mut self) -> crate::Result<()> {
+        loop {
+            let Some(data) = self.frame_data.as_mut() else {
+                return Ok(())
+            };
+            let rest = &mut data[self.frame_pos ..];
+            if rest.is_empty() {
+                return Ok(())
+            }
+            if self.pos < self.len {
+                let n = std::cmp::min(rest.len(), self.len - self.pos);
+                rest[.. n].copy_from_slice(&self.buffer[self.pos .. self.pos + n]);
+                self.pos += n;
+                self.frame_pos += n;
+            } else if rest.len() >= self.buffer.len() {
+                // Large frames bypass the buffer
+                let n = self.reader.read(rest).await?;
+                if n == 0 {
+                    return Err(Error::UnexpectedEof);
+                }
+                self.frame_pos += n;
+            } else {
+                self.fill_buffer().await?;
+            }
+        }
+    }
+    async fn skip_frame_data(&mut self) -> crate::Result<()> {
+        loop {
+            let k = std::cmp::min(self.skip, self.len - self.pos);
+            self.pos += k;
+            self.skip -= k;
+            if self.skip == 0 {
+                return Ok(());
+            }
+            self.fill_buffer().await?;
         }
     }
+    async fn read_frame_len(&mut self) -> crate::Result<usize> {
+        loop {
+            let mut buffrd = self.buffered();
+            let mut rd = ChainPackReader::new(&mut buffrd);
+            match rd.read_uint_data() {
+                Ok(len) => {
+                    self.pos += rd.position();
+                    return Ok(len as usize)
+                }
+                Err(err) => {
+                    match err.reason {
+                        ReadErrorReason::UnexpectedEndOfStream => { self.fill_buffer().await? }
+                        ReadErrorReason::InvalidCharacter => {
+                            // there is no way to find the next frame start
+                            self.framing_error = Some(err.msg.clone());
+                            return Err(Error::Framing(err.msg))
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+#[cfg(feature = "tokio")]
+impl<R: tokio::io::AsyncRead + Unpin + Send> StreamFrameReader<Compat<R>> {
+    pub fn from_tokio(reader: R) -> Self {
+        Self::new(reader.compat())
+    }
 }
 #[async_trait]
 impl<R: AsyncRead + Unpin + Send> FrameReader for StreamFrameReader<R> {
+    /// This method is cancel safe, a partially received frame is kept in the reader
+    /// and the reception continues with the next call.
+    ///
+    /// If the call is cancelled while an oversized frame is skipped, the next call
+    /// finishes the skipping without reporting [`Error::FrameTooLarge`] again.
+    ///
+    /// An invalid length prefix is fatal, the [`Error::Framing`] is returned by this
+    /// and every following call.
     async fn receive_frame(&mut self) -> crate::Result<RpcFrame> {
-        let mut lendata: Vec<u8> = vec![];
-        let frame_len = loop {
-            lendata.push(self.get_byte().await?);
-            //let mut cursor = Cursor::new(&lendata);
-            let mut buffrd = BufReader::new(&lendata[..]);
+        if let Some(msg) = &self.framing_error {
+            return Err(Error::Framing(msg.clone()))
+        }
+        if self.skip > 0 {
+            self.skip_frame_data().await?;
+        }
+        if self.frame_data.is_none() {
+            let frame_len = self.read_frame_len().await?;
+            if frame_len > self.max_frame_size {
+                self.skip = frame_len;
+                self.skip_frame_data().await?;
+                return Err(Error::FrameTooLarge { size: frame_len, max_size: self.max_frame_size });
+            }
+            self.frame_data = Some(vec![0u8; frame_len]);
+        }
+        self.read_frame_data().await?;
+        self.frame_pos = 0;
+        let data = self.frame_data.take().unwrap_or_default();
+        let frame = parse_frame_data(data)?;
+        log!(target: "RpcMsg", Level::Debug, "R==> {}", &frame);
+        Ok(frame)
+    }
+}
 
+/// Decodes length prefixed frames.
+///
+/// Pushed bytes are copied straight into the data of the frame being received.
+/// An invalid length prefix is fatal, the frames decoded before it are returned
+/// and then [`Error::Framing`] on every call, further pushed data is ignored.
+pub struct StreamFrameDecoder {
+    max_frame_size: usize,
+    // Length prefix received so far
+    header: Vec<u8>,
+    // Data of the frame being received and its length
+    frame_data: Option<Vec<u8>>,
+    frame_len: usize,
+    // Remaining bytes of an oversized frame
+    skip: usize,
+    frames: VecDeque<crate::Result<RpcFrame>>,
+    framing_error: Option<String>,
+}
+impl Default for StreamFrameDecoder {
+    fn default() -> Self {
+        Self {
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            header: vec![],
+            frame_data: None,
+            frame_len: 0,
+            skip: 0,
+            frames: VecDeque::new(),
+            framing_error: None,
+        }
+    }
+}
+impl StreamFrameDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn with_max_frame_size(mut self, max_frame_size: usize) -> Self {
+        self.max_frame_size = max_frame_size;
+        self
+    }
+    fn take_frame(&mut self) {
+        let Some(data) = self.frame_data.take_if(|data| data.len() == self.frame_len) else {
+            return
+        };
+        let frame = parse_frame_data(data);
+        if let Ok(frame) = &frame {
+            log!(target: "RpcMsg", Level::Debug, "R==> {}", frame);
+        }
+        self.frames.push_back(frame);
+    }
+}
+impl FrameDecoder for StreamFrameDecoder {
+    fn push(&mut self, mut data: &[u8]) {
+        if self.framing_error.is_some() {
+            return
+        }
+        while !data.is_empty() {
+            if self.skip > 0 {
+                let n = std::cmp::min(self.skip, data.len());
+                data = &data[n ..];
+                self.skip -= n;
+                continue;
+            }
+            if let Some(frame_data) = self.frame_data.as_mut() {
+                let n = std::cmp::min(self.frame_len - frame_data.len(), data.len());
+                frame_data.extend_from_slice(&data[.. n]);
+                data = &data[n ..];
+                self.take_frame();
+                continue;
+            }
+            self.header.push(data[0]);
+            data = &data[1 ..];
+            let mut buffrd = &self.header[..];
             let mut rd = ChainPackReader::new(&mut buffrd);
             match rd.read_uint_data() {
-                Ok(len) => { break len as usize }
+                Ok(len) => {
+                    self.header.clear();
+                    let frame_len = len as usize;
+                    if frame_len > self.max_frame_size {
+                        self.skip = frame_len;
+                        self.frames.push_back(Err(Error::FrameTooLarge { size: frame_len, max_size: self.max_frame_size }));
+                    } else {
+                        self.frame_len = frame_len;
+                        self.frame_data = Some(Vec::with_capacity(frame_len));
+                        self.take_frame();
+                    }
+                }
                 Err(err) => {
-                    let msg = err.to_string();
-                    let ReadError{reason, .. } = err;
-                    match reason {
-                        ReadErrorReason::UnexpectedEndOfStream => { continue }
-                        ReadErrorReason::InvalidCharacter => { return Err(msg.into()) }
+                    match err.reason {
+                        ReadErrorReason::UnexpectedEndOfStream => { }
+                        ReadErrorReason::InvalidCharacter => {
+                            // there is no way to find the next frame start
+                            self.header.clear();
+                            self.framing_error = Some(err.msg);
+                            return;
+                        }
                     }
                 }
-            };
-        };
-        let mut data: Vec<u8> = Vec::with_capacity(frame_len);
-        let mut to_read = frame_len;
-        while to_read > 0 {
-            let mut buff = vec![0u8; to_read];
-            let n = self.reader.read(&mut buff[..]).await?;
-            data.append(&mut buff);
-            to_read -= n;
-        }
-        let protocol = data[0];
-        if protocol != Protocol::ChainPack as u8 {
-            return Err("Not chainpack message".into());
-        }
-        let mut buffrd = BufReader::new(&data[1..]);
-        let mut rd = ChainPackReader::new(&mut buffrd);
-        if let Ok(Some(meta)) = rd.try_read_meta() {
-            let pos = rd.position() + 1;
-            let data: Vec<_> = data.drain(pos .. ).collect();
-            let frame = RpcFrame { protocol: Protocol::ChainPack, meta, data };
-            log!(target: "RpcMsg", Level::Debug, "R==> {}", &frame);
-            return Ok(frame);
-        }
-        return Err("Meta data read error".into());
+            }
+        }
+    }
+    fn next_frame(&mut self) -> crate::Result<Option<RpcFrame>> {
+        match (self.frames.pop_front(), &self.framing_error) {
+            (Some(frame), _) => { frame.map(Some) }
+            (None, Some(msg)) => { Err(Error::Framing(msg.clone())) }
+            (None, None) => { Ok(None) }
+        }
+    }
+    fn has_partial_frame(&self) -> bool {
+        !self.header.is_empty() || self.frame_data.is_some() || self.skip > 0
     }
 }
 
 pub fn read_frame(buff: &[u8]) -> crate::Result<RpcFrame> {
-    let mut buffrd = BufReader::new(buff);
+    let mut buffrd = buff;
     let mut rd = ChainPackReader::new(&mut buffrd);
     let frame_len = match rd.read_uint_data() {
         Ok(len) => { len as usize }
         Err(err) => {
-            return Err(err.msg.into());
+            return Err(Error::Framing(err.msg));
         }
     };
     let pos = rd.position();
-    let data = &buff[pos .. pos + frame_len];
-    let protocol = if data[0] == 0 {Protocol::ResetSession} else { Protocol::ChainPack };
-    let data = &data[1 .. ];
-    let mut buffrd = BufReader::new(data);
-    let mut rd = ChainPackReader::new(&mut buffrd);
-    if let Ok(Some(meta)) = rd.try_read_meta() {
-        let pos = rd.position();
-        let frame = RpcFrame { protocol, meta, data: data[pos ..].to_vec() };
-        log!(target: "RpcMsg", Level::Debug, "R==> {}", &frame);
-        return Ok(frame);
-    }
-    Err("Meta data read error".into())
+    let data = pos.checked_add(frame_len)
+        .and_then(|end| buff.get(pos .. end))
+        .ok_or(Error::UnexpectedEof)?;
+    let frame = parse_frame_data(data.to_vec())?;
+    log!(target: "RpcMsg", Level::Debug, "R==> {}", &frame);
+    Ok(frame)
 }
 
 pub struct StreamFrameWriter<W: AsyncWrite + Unpin + Send> {
@@ -108,19 +303,22 @@ impl<W: AsyncWrite + Unpin + Send> StreamFrameWriter<W> {
     }
 }
 
+#[cfg(feature = "tokio")]
+impl<W: tokio::io::AsyncWrite + Unpin + Send> StreamFrameWriter<Compat<W>> {
+    pub fn from_tokio(writer: W) -> Self {
+        Self::new(writer.compat_write())
+    }
+}
 #[async_trait]
 impl<W: AsyncWrite + Unpin + Send> FrameWriter for StreamFrameWriter<W> {
     async fn send_frame(&mut self, frame: RpcFrame) -> crate::Result<()> {
-        log!(target: "RpcMsg", Level::Debug, "S<== {}", &frame.to_rpcmesage().unwrap_or_default());
-        let meta_data = serialize_meta(&frame)?;
+        log!(target: "RpcMsg", Level::Debug, "S<== {}", &frame);
+        let data = serialize_frame(&frame)?;
         let mut header = Vec::new();
         let mut wr = ChainPackWriter::new(&mut header);
-        let msg_len = 1 + meta_data.len() + frame.data.len();
-        wr.write_uint_data(msg_len as u64)?;
-        header.push(frame.protocol as u8);
+        wr.write_uint_data(data.len() as u64)?;
         self.writer.write_all(&header).await?;
-        self.writer.write_all(&meta_data).await?;
-        self.writer.write_all(&frame.data).await?;
+        self.writer.write_all(&data).await?;
         // Ensure the encoded frame is written to the socket. The calls above
         // are to the buffered stream and writes. Calling `flush` writes the
         // remaining contents of the buffer to the socket.
@@ -130,17 +328,162 @@ impl<W: AsyncWrite + Unpin + Send> FrameWriter for StreamFrameWriter<W> {
 }
 
 pub fn write_frame(buff: &mut Vec<u8>, frame: RpcFrame) -> crate::Result<()> {
-    let mut meta_data = serialize_meta(&frame)?;
-    let mut header = Vec::new();
-    let mut wr = ChainPackWriter::new(&mut header);
-    let msg_len = 1 + meta_data.len() + frame.data.len();
-    wr.write_uint_data(msg_len as u64)?;
-    header.push(frame.protocol as u8);
-    let mut frame = frame;
-    buff.append(&mut header);
-    buff.append(&mut meta_data);
-    buff.append(&mut frame.data);
+    let mut data = serialize_frame(&frame)?;
+    let mut wr = ChainPackWriter::new(buff);
+    wr.write_uint_data(data.len() as u64)?;
+    buff.append(&mut data);
     Ok(())
 }
 
 
+
+#[cfg(all(test, feature = "async-std"))]
+mod test {
+    use shvproto::RpcValue;
+    use shvproto::MetaMap;
+    use crate::RpcMessage;
+    use crate::rpcframe::Protocol;
+    use crate::testutil::{receive_frame_cancelled, ChunkedReader};
+    use super::*;
+
+    #[async_std::test]
+    async fn test_receive_frame() {
+        let frames: Vec<_> = [
+            RpcMessage::new_request("foo/bar", "baz", Some("hello".into())),
+            RpcMessage::new_request("foo/bar", "blob", Some(RpcValue::from(vec![0x55u8; 3 * READ_BUFFER_SIZE]))),
+            RpcMessage::new_signal("foo/bar", "chng", Some(42.into())),
+        ].iter().map(|msg| msg.to_frame().unwrap()).collect();
+        let mut data = vec![];
+        for frame in &frames {
+            write_frame(&mut data, frame.clone()).unwrap();
+        }
+        for chunk_size in [1, 3, 1000, usize::MAX] {
+            let mut rd = StreamFrameReader::new(ChunkedReader::new(data.clone(), chunk_size));
+            for frame in &frames {
+                assert_eq!(&rd.receive_frame().await.unwrap(), frame);
+            }
+            assert!(rd.receive_frame().await.is_err());
+        }
+    }
+
+    #[test]
+    fn test_cancel_safety() {
+        let frames: Vec<_> = [
+            RpcMessage::new_request("foo/bar", "baz", Some("hello".into())),
+            RpcMessage::new_request("foo/bar", "blob", Some(RpcValue::from(vec![0x55u8; 3 * READ_BUFFER_SIZE]))),
+            RpcMessage::new_signal("foo/bar", "chng", Some(42.into())),
+        ].iter().map(|msg| msg.to_frame().unwrap()).collect();
+        let mut data = vec![];
+        for frame in &frames {
+            write_frame(&mut data, frame.clone()).unwrap();
+        }
+        for chunk_size in [1, 3, 1000, usize::MAX] {
+            let mut rd = StreamFrameReader::new(ChunkedReader::interrupted(data.clone(), chunk_size));
+            for frame in &frames {
+                assert_eq!(&receive_frame_cancelled(&mut rd).unwrap(), frame);
+            }
+        }
+    }
+
+    #[async_std::test]
+    async fn test_max_frame_size() {
+        let small = RpcMessage::new_request("foo/bar", "baz", None).to_frame().unwrap();
+        let large = RpcMessage::new_request("foo/bar", "baz", Some(RpcValue::from(vec![0u8; 2 * READ_BUFFER_SIZE]))).to_frame().unwrap();
+        let mut data = vec![];
+        for frame in [&large, &small] {
+            write_frame(&mut data, frame.clone()).unwrap();
+        }
+        let mut rd = StreamFrameReader::new(ChunkedReader::new(data, 100)).with_max_frame_size(READ_BUFFER_SIZE);
+        let err = rd.receive_frame().await.unwrap_err();
+        assert!(matches!(err, Error::FrameTooLarge { .. }));
+        assert_eq!(rd.receive_frame().await.unwrap(), small);
+    }
+
+    #[async_std::test]
+    async fn test_invalid_frame_len() {
+        let frame = RpcMessage::new_signal("foo/bar", "chng", Some(42.into())).to_frame().unwrap();
+        let mut data = vec![];
+        write_frame(&mut data, frame.clone()).unwrap();
+        data.extend_from_slice(&[0xff, 1, 2, 3]);
+        write_frame(&mut data, frame.clone()).unwrap();
+        for chunk_size in [1, 3, 1000] {
+            let mut rd = StreamFrameReader::new(ChunkedReader::new(data.clone(), chunk_size));
+            assert_eq!(rd.receive_frame().await.unwrap(), frame);
+            for _ in 0 .. 2 {
+                assert!(matches!(rd.receive_frame().await, Err(Error::Framing(_))));
+            }
+            let mut decoder = StreamFrameDecoder::new();
+            for chunk in data.chunks(chunk_size) {
+                decoder.push(chunk);
+            }
+            assert_eq!(decoder.next_frame().unwrap(), Some(frame.clone()));
+            for _ in 0 .. 2 {
+                assert!(matches!(decoder.next_frame(), Err(Error::Framing(_))));
+            }
+        }
+    }
+
+    #[async_std::test]
+    async fn test_reset_session() {
+        let mut data = vec![];
+        StreamFrameWriter::new(&mut data).send_reset_session().await.unwrap();
+        assert_eq!(data, [1, 0]);
+        let mut rd = StreamFrameReader::new(&*data);
+        assert!(rd.receive_frame().await.unwrap().is_reset_session());
+        let mut rd = StreamFrameReader::new(&*data);
+        assert!(matches!(rd.receive_message().await, Err(Error::ResetSession)));
+    }
+
+    #[async_std::test]
+    async fn test_protocols() {
+        let msg = RpcMessage::new_request("test/device", "set", Some(RpcValue::from(vec![RpcValue::from(1), RpcValue::from("foo")])));
+        for protocol in [Protocol::ChainPack, Protocol::Cpon, Protocol::Json] {
+            let mut data = vec![];
+            StreamFrameWriter::new(&mut data).send_frame(msg.to_frame_with_protocol(protocol).unwrap()).await.unwrap();
+            let frame = StreamFrameReader::new(&*data).receive_frame().await.unwrap();
+            assert_eq!(frame.protocol, protocol);
+            assert_eq!(frame.to_rpcmesage().unwrap().to_cpon(), msg.to_cpon());
+        }
+        let frame = RpcFrame::new(Protocol::Unknown(9), MetaMap::new(), vec![1, 2, 3]);
+        let mut data = vec![];
+        write_frame(&mut data, frame.clone()).unwrap();
+        assert_eq!(data, [4, 9, 1, 2, 3]);
+        let mut rd = StreamFrameReader::new(&*data);
+        assert_eq!(rd.receive_frame().await.unwrap(), frame);
+        assert!(matches!(frame.to_rpcmesage(), Err(Error::UnsupportedProtocol(9))));
+    }
+
+    proptest::proptest! {
+        #[test]
+        fn test_read_frame_random_data(data: Vec<u8>) {
+            let _ = read_frame(&data);
+        }
+
+        #[test]
+        fn test_frame_decoder_random_data(data: Vec<u8>, chunk_size in 1 .. 64usize) {
+            let mut decoder = StreamFrameDecoder::new().with_max_frame_size(1024);
+            for chunk in data.chunks(chunk_size) {
+                decoder.push(chunk);
+                while let Ok(Some(frame)) = decoder.next_frame() {
+                    let _ = frame.to_rpcmesage();
+                }
+            }
+        }
+
+        #[test]
+        fn test_frame_decoder_round_trip(param: Vec<u8>, chunk_size in 1 .. 64usize) {
+            let frame = RpcMessage::new_request("foo/bar", "baz", Some(RpcValue::from(param))).to_frame().unwrap();
+            let mut data = vec![];
+            write_frame(&mut data, frame.clone()).unwrap();
+            let mut decoder = StreamFrameDecoder::new();
+            let mut frames = vec![];
+            for chunk in data.chunks(chunk_size) {
+                decoder.push(chunk);
+                while let Some(frame) = decoder.next_frame().unwrap() {
+                    frames.push(frame);
+                }
+            }
+            proptest::prop_assert_eq!(frames, vec![frame]);
+        }
+    }
+}
@@ -0,0 +1,51 @@
+//! Helpers shared by the unit tests.
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use futures::{AsyncRead, FutureExt};
+use crate::framerw::FrameReader;
+use crate::rpcframe::RpcFrame;
+
+/// Returns at most `chunk_size` bytes per read call, every other call is pending if `interrupt` is set.
+pub struct ChunkedReader {
+    data: Vec<u8>,
+    pos: usize,
+    chunk_size: usize,
+    interrupt: bool,
+    pending: bool,
+    /// Number of read calls.
+    pub reads: usize,
+}
+impl ChunkedReader {
+    pub fn new(data: Vec<u8>, chunk_size: usize) -> Self {
+        Self { data, pos: 0, chunk_size, interrupt: false, pending: false, reads: 0 }
+    }
+    pub fn interrupted(data: Vec<u8>, chunk_size: usize) -> Self {
+        Self { interrupt: true, ..Self::new(data, chunk_size) }
+    }
+}
+impl AsyncRead for ChunkedReader {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        self.reads += 1;
+        if self.interrupt {
+            self.pending = !self.pending;
+            if self.pending {
+                cx.waker().wake_by_ref();
+                return Poll::Pending
+            }
+        }
+        let n = self.chunk_size.min(buf.len()).min(self.data.len() - self.pos);
+        buf[.. n].copy_from_slice(&self.data[self.pos .. self.pos + n]);
+        self.pos += n;
+        Poll::Ready(Ok(n))
+    }
+}
+
+/// Receives a frame dropping each pending `receive_frame()` future, like a `select!` with a timeout does.
+pub fn receive_frame_cancelled(reader: &mut (impl FrameReader + Send)) -> crate::Result<RpcFrame> {
+    loop {
+        if let Some(res) = reader.receive_frame().now_or_never() {
+            return res
+        }
+    }
+}